@@ -0,0 +1,84 @@
+use crate::Grid;
+
+// Occupancy mask packing eight cells per byte, in the same x fastest order
+// as Grid
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BitGrid {
+    width: u32,
+    depth: u32,
+    height: u32,
+    bits: Vec<u8>,
+}
+
+impl BitGrid {
+    pub fn new(width: u32, depth: u32, height: u32) -> BitGrid {
+        let len = (width as usize)
+            .checked_mul(depth as usize)
+            .and_then(|len| len.checked_mul(height as usize));
+        match len {
+            None => panic!("BitGrid len overflows usize"),
+            Some(len) => BitGrid { width, depth, height, bits: vec![0; len.div_ceil(8)] },
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn id(&self, x: u32, y: u32, z: u32) -> usize {
+        if x >= self.width || y >= self.depth || z >= self.height {
+            panic!(
+                "BitGrid index {:?} out of bounds {:?}",
+                (x, y, z),
+                (self.width, self.depth, self.height)
+            );
+        }
+        (z as usize * self.depth as usize + y as usize) * self.width as usize + x as usize
+    }
+
+    pub fn get(&self, x: u32, y: u32, z: u32) -> bool {
+        let id = self.id(x, y, z);
+        self.bits[id / 8] & (1 << (id % 8)) != 0
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, z: u32, value: bool) {
+        let id = self.id(x, y, z);
+        if value {
+            self.bits[id / 8] |= 1 << (id % 8);
+        } else {
+            self.bits[id / 8] &= !(1 << (id % 8));
+        }
+    }
+
+    // Coordinates of the set cells in storage order, skipping empty bytes
+    pub fn enumerate_set(&self) -> impl Iterator<Item = (u32, u32, u32)> + '_ {
+        let (width, depth) = (self.width as usize, self.depth as usize);
+        self.bits
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte != 0)
+            .flat_map(|(i, byte)| (0..8).filter(move |bit| byte & (1 << bit) != 0).map(move |bit| i * 8 + bit))
+            .map(move |id| ((id % width) as u32, (id / width % depth) as u32, (id / width / depth) as u32))
+    }
+}
+
+// Sets the cells that are nonzero in the mask
+impl From<&Grid<u8>> for BitGrid {
+    fn from(mask: &Grid<u8>) -> BitGrid {
+        let mut bits = BitGrid::new(mask.width(), mask.depth(), mask.height());
+        for (x, y, z, v) in mask.enumerate_cells() {
+            if *v != 0 {
+                bits.set(x, y, z, true);
+            }
+        }
+        bits
+    }
+}
@@ -1,5 +1,13 @@
+pub mod bitgrid;
+pub mod mesh;
+pub mod octree;
 pub mod vox;
 
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{Read, Write};
+use std::hash::Hash;
 use std::marker::PhantomData;
 use std::ops::Range;
 use std::slice::{ChunksExact, ChunksExactMut};
@@ -12,7 +20,36 @@ pub trait Codec {
     fn from_slice_mut(slice: &mut [u8]) -> &mut Self;
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+// Builds a Grid<Voxel> from layers of rows of character codes, looked up in a
+// palette of (char, rgba) pairs. Characters run along x, rows along y and
+// layers along z. Ragged rows or layers fail to type check.
+//
+// let grid = grid![&[('r', [255, 0, 0, 255]), ('.', [0; 4])];
+//     [['r', '.'],
+//      ['.', 'r']],
+// ];
+#[macro_export]
+macro_rules! grid {
+    ($palette:expr; $([$([$($code:expr),+ $(,)?]),+ $(,)?]),+ $(,)?) => {{
+        let layers = [$([$([$($code),+]),+]),+];
+        let palette: &[(char, [u8; 4])] = $palette;
+        let mut grid = $crate::Grid::<$crate::Voxel>::new(
+            layers[0][0].len() as u32,
+            layers[0].len() as u32,
+            layers.len() as u32,
+        );
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            let code = layers[z as usize][y as usize][x as usize];
+            match palette.iter().find(|(c, _)| *c == code) {
+                None => panic!("Grid code {:?} missing from palette", code),
+                Some((_, rgba)) => *v = $crate::Voxel::from_rgba(rgba),
+            }
+        }
+        grid
+    }};
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Voxel([u8; 8]);
 
 impl Voxel {
@@ -23,6 +60,150 @@ impl Voxel {
     pub fn as_rgba(&self) -> &[u8] {
         &self.0[0..4]
     }
+
+    // The emissive flag is the low bit of the first byte after RGBA
+    pub fn set_emissive(&mut self, emissive: bool) {
+        self.0[4] = (self.0[4] & !1) | u8::from(emissive);
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.0[4] & 1 != 0
+    }
+
+    // Scales RGB by alpha / 255
+    pub fn premultiply(&self) -> Voxel {
+        let mut voxel = *self;
+        let a = self.0[3] as u32;
+        for c in &mut voxel.0[0..3] {
+            *c = ((*c as u32 * a + 127) / 255) as u8;
+        }
+        voxel
+    }
+
+    // Inverse of premultiply, fully transparent voxels become black
+    pub fn unpremultiply(&self) -> Voxel {
+        let mut voxel = *self;
+        let a = self.0[3] as u32;
+        for c in &mut voxel.0[0..3] {
+            *c = (*c as u32 * 255 + a / 2).checked_div(a).unwrap_or(0).min(255) as u8;
+        }
+        voxel
+    }
+
+    // Composites self over below using straight alpha, blending internally in
+    // premultiplied space so transparent colors don't darken the result
+    pub fn blend_over(&self, below: &Voxel) -> Voxel {
+        let (src, dst) = (self.premultiply(), below.premultiply());
+        let inverse = 255 - src.0[3] as u32;
+        let mut voxel = *self;
+        for i in 0..4 {
+            voxel.0[i] = (src.0[i] as u32 + (dst.0[i] as u32 * inverse + 127) / 255).min(255) as u8;
+        }
+        voxel.unpremultiply()
+    }
+
+    // Hue in degrees from 0 to 360, saturation and value from 0 to 1
+    pub fn to_hsv(&self) -> (f32, f32, f32) {
+        let [r, g, b] = [self.0[0], self.0[1], self.0[2]].map(|c| c as f32 / 255.0);
+        let max = r.max(g).max(b);
+        let delta = max - r.min(g).min(b);
+        let hue = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        let saturation = if max == 0.0 { 0.0 } else { delta / max };
+        (hue, saturation, max)
+    }
+
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32, alpha: u8) -> Voxel {
+        let h = hue.rem_euclid(360.0) / 60.0;
+        let chroma = value * saturation;
+        let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = value - chroma;
+        let [r, g, b] = [r, g, b].map(|c| ((c + m) * 255.0).round().clamp(0.0, 255.0) as u8);
+        Voxel::from_rgba(&[r, g, b, alpha])
+    }
+
+    // Keeps alpha and the bytes after RGBA
+    pub fn rotate_hue(&self, degrees: f32) -> Voxel {
+        let (hue, saturation, value) = self.to_hsv();
+        let rotated = Voxel::from_hsv(hue + degrees, saturation, value, self.0[3]);
+        let mut voxel = *self;
+        voxel.0[0..3].copy_from_slice(&rotated.0[0..3]);
+        voxel
+    }
+
+    // Averages the RGB channels in the given color space, alpha is always
+    // averaged directly
+    pub fn average(voxels: &[Voxel], space: ColorSpace) -> Voxel {
+        let mut sum = [0.0; 4];
+        for v in voxels {
+            for (i, c) in v.as_rgba().iter().enumerate() {
+                sum[i] += match (i, &space) {
+                    (0..=2, ColorSpace::Linear) => srgb_to_linear(*c),
+                    _ => *c as f32 / 255.0,
+                };
+            }
+        }
+        let mut rgba = [0; 4];
+        for (i, s) in sum.iter().enumerate() {
+            let c = s / voxels.len().max(1) as f32;
+            let c = match (i, &space) {
+                (0..=2, ColorSpace::Linear) => linear_to_srgb(c),
+                _ => c,
+            };
+            rgba[i] = (c * 255.0).round() as u8;
+        }
+        Voxel::from_rgba(&rgba)
+    }
+}
+
+impl From<[u8; 4]> for Voxel {
+    fn from(rgba: [u8; 4]) -> Voxel {
+        Voxel([rgba[0], rgba[1], rgba[2], rgba[3], 0, 0, 0, 0])
+    }
+}
+
+impl From<Voxel> for [u8; 4] {
+    fn from(voxel: Voxel) -> [u8; 4] {
+        [voxel.0[0], voxel.0[1], voxel.0[2], voxel.0[3]]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
 }
 
 impl Codec for Voxel {
@@ -44,6 +225,42 @@ impl Codec for Voxel {
     }
 }
 
+impl Codec for u8 {
+    const SIZE: u8 = 1;
+
+    #[inline(always)]
+    fn as_slice(&self) -> &[u8] {
+        std::slice::from_ref(self)
+    }
+
+    fn from_slice(slice: &[u8]) -> &u8 {
+        assert_eq!(slice.len(), Self::SIZE as usize);
+        &slice[0]
+    }
+
+    fn from_slice_mut(slice: &mut [u8]) -> &mut u8 {
+        assert_eq!(slice.len(), Self::SIZE as usize);
+        &mut slice[0]
+    }
+}
+
+impl Codec for [u8; 4] {
+    const SIZE: u8 = 4;
+
+    #[inline(always)]
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    fn from_slice(slice: &[u8]) -> &[u8; 4] {
+        slice.try_into().unwrap()
+    }
+
+    fn from_slice_mut(slice: &mut [u8]) -> &mut [u8; 4] {
+        slice.try_into().unwrap()
+    }
+}
+
 impl Codec for u32 {
     const SIZE: u8 = (u32::BITS / 8) as u8;
 
@@ -91,10 +308,141 @@ pub enum Rotation {
     R270,
 }
 
+// One of the 24 rotations of a grid, as a signed permutation matrix mapping
+// source axes to output axes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Orientation([[i32; 3]; 3]);
+
+impl Orientation {
+    pub const IDENTITY: Orientation = Orientation([[1, 0, 0], [0, 1, 0], [0, 0, 1]]);
+
+    pub fn all() -> Vec<Orientation> {
+        let permutations = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+        let mut orientations = Vec::with_capacity(24);
+        for permutation in permutations {
+            for signs in 0..8 {
+                let mut m = [[0; 3]; 3];
+                for (i, j) in permutation.into_iter().enumerate() {
+                    m[i][j] = if signs & (1 << i) == 0 { 1 } else { -1 };
+                }
+                let orientation = Orientation(m);
+                if orientation.determinant() == 1 {
+                    orientations.push(orientation);
+                }
+            }
+        }
+        orientations
+    }
+
+    // Counterclockwise rotation looking down the axis from its positive end
+    pub fn about(axis: Axis, rotation: &Rotation) -> Orientation {
+        let quarter = match axis {
+            Axis::X => [[1, 0, 0], [0, 0, -1], [0, 1, 0]],
+            Axis::Y => [[0, 0, 1], [0, 1, 0], [-1, 0, 0]],
+            Axis::Z => [[0, -1, 0], [1, 0, 0], [0, 0, 1]],
+        };
+        let turns = match rotation {
+            Rotation::R0 => 0,
+            Rotation::R90 => 1,
+            Rotation::R180 => 2,
+            Rotation::R270 => 3,
+        };
+        let mut m = Orientation::IDENTITY.0;
+        for _ in 0..turns {
+            m = std::array::from_fn(|i| {
+                std::array::from_fn(|j| (0..3).map(|k| quarter[i][k] * m[k][j]).sum())
+            });
+        }
+        Orientation(m)
+    }
+
+    fn determinant(&self) -> i32 {
+        let m = self.0;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+}
+
+impl From<&Rotation> for Orientation {
+    fn from(rotation: &Rotation) -> Orientation {
+        Orientation::about(Axis::Z, rotation)
+    }
+}
+
+// Which half wins when symmetrizing: the low or high x half, or whichever
+// side is opaque, preferring the low half when both are
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SymPref {
+    KeepLow,
+    KeepHigh,
+    Union,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+// Which cells count as adjacent: sharing a face (6), a face or edge (18), or
+// any face, edge or vertex (26)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Connectivity {
+    Face,
+    Edge,
+    Vertex,
+}
+
+// Inclusive min and max corners of a box of cells
+pub type Bounds = ((u32, u32, u32), (u32, u32, u32));
+
+// Why ASCII layers could not be parsed, with layers, rows and columns
+// counted from 0
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseError {
+    NoLayers,
+    RowCount { layer: usize, expected: usize, actual: usize },
+    RowLength { layer: usize, row: usize, expected: usize, actual: usize },
+    UnknownChar { layer: usize, row: usize, column: usize, char: char },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::NoLayers => write!(f, "Grid ASCII has no layers"),
+            ParseError::RowCount { layer, expected, actual } => {
+                write!(f, "Grid ASCII layer {} has {} rows, expected {}", layer, actual, expected)
+            },
+            ParseError::RowLength { layer, row, expected, actual } => write!(
+                f,
+                "Grid ASCII layer {} row {} has {} columns, expected {}",
+                layer, row, actual, expected
+            ),
+            ParseError::UnknownChar { layer, row, column, char } => write!(
+                f,
+                "Grid ASCII {:?} at layer {} row {} column {} missing from palette",
+                char, layer, row, column
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+const NATIVE_VERSION: u8 = 1;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Grid<T> {
     width: u32,
     depth: u32,
     height: u32,
+    // Cells per z layer, cached from width and depth for index math
+    layer: usize,
+    // Bytes per row and per z layer, cached with T::SIZE for byte offsets
+    row_bytes: usize,
+    layer_bytes: usize,
     data: Vec<u8>,
     _phantom: PhantomData<T>,
 }
@@ -107,9 +455,12 @@ where
             None => panic!("Grid len overflows usize"),
             Some(len) => {
                 Grid {
-                    width: width,
-                    depth: depth,
-                    height: height,
+                    width,
+                    depth,
+                    height,
+                    layer: width as usize * depth as usize,
+                    row_bytes: width as usize * <T>::SIZE as usize,
+                    layer_bytes: width as usize * depth as usize * <T>::SIZE as usize,
                     data: vec![0; len],
                     _phantom: PhantomData,
                 }
@@ -163,7 +514,7 @@ where
             None => panic!(
                 "Grid id {:?} out of bounds {:?}",
                 id,
-                self.width * self.depth * self.height
+                self.cell_count()
             ),
             Some(indices) => <T>::from_slice(&self.data[indices]),
         }
@@ -174,15 +525,20 @@ where
             None => panic!(
                 "Grid id {:?} out of bounds {:?}",
                 id,
-                self.width * self.depth * self.height
+                self.cell_count()
             ),
             Some(indices) => <T>::from_slice_mut(&mut self.data[indices]),
         }
     }
 
+    #[inline(always)]
+    pub fn contains(&self, x: u32, y: u32, z: u32) -> bool {
+        x < self.width && y < self.depth && z < self.height
+    }
+
     #[inline(always)]
     pub fn id(&self, x: u32, y: u32, z: u32) -> Option<usize> {
-        if x >= self.width || y >= self.depth || z >= self.height {
+        if !self.contains(x, y, z) {
             return None;
         }
         Some(self.id_unchecked(x, y, z))
@@ -192,12 +548,12 @@ where
     fn id_unchecked(&self, x: u32, y: u32, z: u32) -> usize {
         x as usize
         + (y as usize * self.width as usize)
-        + (z as usize * self.width as usize * self.depth as usize)
+        + (z as usize * self.layer)
     }
 
     #[inline(always)]
     pub fn coordinate(&self, id: usize) -> Option<(u32, u32, u32)> {
-        if id >= self.width as usize * self.depth as usize * self.height as usize {
+        if id >= self.cell_count() {
             return None;
         }
         Some(self.coordinate_unchecked(id))
@@ -205,25 +561,55 @@ where
 
     #[inline(always)]
     fn coordinate_unchecked(&self, id: usize) -> (u32, u32, u32) {
-        let z = id / (self.width as usize * self.depth as usize);
-        let plane = id - (z * self.width as usize * self.depth as usize);
+        let z = id / self.layer;
+        let plane = id - (z * self.layer);
         let y = plane / self.width as usize;
         let x = plane % self.width as usize;
         (x as u32, y as u32, z as u32)
     }
 
+    // In bounds coordinates adjacent to (x, y, z) under the connectivity
+    fn neighbors(
+        &self,
+        x: u32,
+        y: u32,
+        z: u32,
+        connectivity: Connectivity,
+    ) -> impl Iterator<Item = (u32, u32, u32)> {
+        let (width, depth, height) = (self.width, self.depth, self.height);
+        let max_distance = match connectivity {
+            Connectivity::Face => 1,
+            Connectivity::Edge => 2,
+            Connectivity::Vertex => 3,
+        };
+        (0..27)
+            .map(|i| (i % 3 - 1, i / 3 % 3 - 1, i / 9 - 1))
+            .filter(move |(dx, dy, dz): &(i32, i32, i32)| {
+                let distance = dx.abs() + dy.abs() + dz.abs();
+                distance > 0 && distance <= max_distance
+            })
+            .filter_map(move |(dx, dy, dz)| {
+                let nx = x.checked_add_signed(dx).filter(|nx| *nx < width)?;
+                let ny = y.checked_add_signed(dy).filter(|ny| *ny < depth)?;
+                let nz = z.checked_add_signed(dz).filter(|nz| *nz < height)?;
+                Some((nx, ny, nz))
+            })
+    }
+
     #[inline(always)]
     fn indices(&self, x: u32, y: u32, z: u32) -> Option<Range<usize>> {
-        let id = self.id(x, y, z);
-        match id {
-            None => None,
-            Some(id) => Some(self.indices_unchecked(id)),
+        if !self.contains(x, y, z) {
+            return None;
         }
+        let min_index = x as usize * <T>::SIZE as usize
+            + y as usize * self.row_bytes
+            + z as usize * self.layer_bytes;
+        Some(min_index..min_index + <T>::SIZE as usize)
     }
 
     #[inline(always)]
     fn indices_id(&self, id: usize) -> Option<Range<usize>> {
-        if id >= (self.width * self.depth * self.height) as usize {
+        if id >= self.cell_count() {
             return None;
         }
         Some(self.indices_unchecked(id))
@@ -235,7 +621,7 @@ where
         min_index..min_index + <T>::SIZE as usize
     }
 
-    pub fn enumerate_cells(&self) -> EnumerateCells<T> {
+    pub fn enumerate_cells(&self) -> EnumerateCells<'_, T> {
         EnumerateCells {
             chunks: self
                 .data
@@ -249,7 +635,104 @@ where
         }
     }
 
-    pub fn enumerate_cells_mut(&mut self) -> EnumerateCellsMut<T> {
+    pub fn enumerate_cells_in_z(&self, z_range: Range<u32>) -> EnumerateCells<'_, T> {
+        let end = z_range.end.min(self.height);
+        let start = z_range.start.min(end);
+        let layer_size = self.layer_bytes;
+        EnumerateCells {
+            chunks: self.data[start as usize * layer_size..end as usize * layer_size]
+                .chunks_exact(<T>::SIZE as usize),
+            x: 0,
+            y: 0,
+            z: start,
+            width: self.width,
+            depth: self.depth,
+            _phantom: PhantomData,
+        }
+    }
+
+    // Cells on the plane perpendicular to the axis at index, with their two
+    // in-plane coordinates in x, y, z order. Empty when index is outside.
+    pub fn slice(&self, axis: Axis, index: u32) -> Vec<(u32, u32, &T)> {
+        match axis {
+            Axis::Z => self
+                .enumerate_cells_in_z(index..index.saturating_add(1))
+                .map(|(x, y, _, t)| (x, y, t))
+                .collect(),
+            Axis::X if index < self.width => (0..self.height)
+                .flat_map(|z| (0..self.depth).map(move |y| (y, z)))
+                .map(|(y, z)| (y, z, self.get(index, y, z)))
+                .collect(),
+            Axis::Y if index < self.depth => (0..self.height)
+                .flat_map(|z| (0..self.width).map(move |x| (x, z)))
+                .map(|(x, z)| (x, z, self.get(x, index, z)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Visits cells with order[0] as the innermost loop and order[2] as the
+    // outermost, so [X, Y, Z] matches storage order
+    pub fn enumerate_order(&self, order: [Axis; 3]) -> impl Iterator<Item = (u32, u32, u32, &T)> {
+        if order[0] == order[1] || order[1] == order[2] || order[0] == order[2] {
+            panic!("Grid order {:?} repeats an axis", order);
+        }
+        let dims = [self.width, self.depth, self.height];
+        let [a, b, c] = order.map(|axis| axis as usize);
+        (0..dims[c]).flat_map(move |k| {
+            (0..dims[b]).flat_map(move |j| {
+                (0..dims[a]).map(move |i| {
+                    let mut p = [0; 3];
+                    (p[a], p[b], p[c]) = (i, j, k);
+                    (p[0], p[1], p[2], self.get(p[0], p[1], p[2]))
+                })
+            })
+        })
+    }
+
+    // Visits cells in Morton (Z-order) with x in the lowest interleaved bit.
+    // Walks the octants of the enclosing power of two cube, only descending
+    // into those overlapping the grid, so flat or thin grids cost no more
+    // than their cells.
+    pub fn enumerate_morton(&self) -> impl Iterator<Item = (u32, u32, u32, &T)> {
+        let dims = [self.width, self.depth, self.height];
+        let side = self.width.max(self.depth).max(self.height).max(1).next_power_of_two();
+        // (min corner, side) of each octant still to visit
+        let mut octants = Vec::new();
+        if self.cell_count() > 0 {
+            octants.push(([0u32; 3], side));
+        }
+        std::iter::from_fn(move || {
+            while let Some((origin, side)) = octants.pop() {
+                if side == 1 {
+                    let [x, y, z] = origin;
+                    return Some((x, y, z, self.get(x, y, z)));
+                }
+                let half = side / 2;
+                // Pushed last to first so the lowest code is popped first
+                for child in (0..8).rev() {
+                    let o = [0, 1, 2].map(|axis| origin[axis] + (child >> axis & 1) * half);
+                    if (0..3).all(|axis| o[axis] < dims[axis]) {
+                        octants.push((o, half));
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    // Pairs each cell with the element of other at the same storage index
+    pub fn zip_with<'a, U>(
+        &'a self,
+        other: &'a [U],
+    ) -> impl Iterator<Item = ((u32, u32, u32), &'a T, &'a U)> {
+        if other.len() != self.cell_count() {
+            panic!("Grid zip length {} does not match {} cells", other.len(), self.cell_count());
+        }
+        self.enumerate_cells().zip(other).map(|((x, y, z, t), u)| ((x, y, z), t, u))
+    }
+
+    pub fn enumerate_cells_mut(&mut self) -> EnumerateCellsMut<'_, T> {
         EnumerateCellsMut {
             chunks: self
                 .data
@@ -264,383 +747,3756 @@ where
     }
 
 
-    pub fn cell_count(&self) -> usize {
-        self.width as usize * self.depth as usize * self.height as usize
+    // The bytes of each z layer, which are contiguous since z is outermost
+    pub fn layers_mut(&mut self) -> impl Iterator<Item = (u32, &mut [u8])> {
+        let layer_size = self.layer_bytes;
+        self.data
+            .chunks_exact_mut(layer_size.max(1))
+            .enumerate()
+            .map(|(z, layer)| (z as u32, layer))
     }
 
-    pub fn rotated_z(&self, rotation: &Rotation) -> Grid<T> {
-        let width = self.width();
-        let depth = self.depth();
-        let height = self.height();
-        // TODO: Figure out how to do it for rectangular prisms
-        assert!(width == depth && width == height);
-        let mut output = Grid::new(width, depth, height);
-        let r = match rotation {
-            Rotation::R0 => [[1, 0, 0], [0, 1, 0], [0, 0, 1]],
-            Rotation::R90 => [[0, -1, 0], [1, 0, 0], [0, 0, 1]],
-            Rotation::R180 => [[-1, 0, 0], [0, -1, 0], [0, 0, 1]],
-            Rotation::R270 => [[0, 1, 0], [-1, 0, 0], [0, 0, 1]],
-        };
-        let x_offset = width as i64 / 2;
-        let y_offset = depth as i64 / 2;
-        let z_offset = height as i64 / 2;
-        let x_even_correction = match rotation {
-            Rotation::R90 | Rotation::R180 =>  if width % 2 == 0 { 1 } else { 0 }
-            _ => if depth > width { 1 } else { 0 },
-        };
-        let y_even_correction = match rotation {
-            Rotation::R180 | Rotation::R270 =>  if depth % 2 == 0 { 1 } else { 0 }
-            _ => 0,
-        };
-        for (gx, gy, gz, t) in self.enumerate_cells() {
-            let x = gx as i64 - x_offset;
-            let y = gy as i64 - y_offset;
-            let z = gz as i64 - z_offset;
-            let rx = r[0][0] * x + r[0][1] * y + r[0][2] * z + x_offset - x_even_correction;
-            let ry = r[1][0] * x + r[1][1] * y + r[1][2] * z + y_offset - y_even_correction;
-            let rz = r[2][0] * x + r[2][1] * y + r[2][2] * z + z_offset;
-            *output.get_mut(rx as u32, ry as u32, rz as u32) = *t;
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(u32, u32, u32, &mut T)) {
+        for (x, y, z, t) in self.enumerate_cells_mut() {
+            f(x, y, z, t);
         }
-        output
     }
-}
-
-pub struct EnumerateCells<'a, T> {
-    chunks: ChunksExact<'a, u8>,
-    x: u32,
-    y: u32,
-    z: u32,
-    width: u32,
-    depth: u32,
-    _phantom: PhantomData<T>,
-}
 
-impl<'a, T> Iterator for EnumerateCells<'a, T>
-where
-    T: Codec + 'a,
-{
-    type Item = (u32, u32, u32, &'a T);
-
-    #[inline(always)]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.x >= self.width {
-            self.x = 0;
-            self.y += 1;
-        }
-        if self.y >= self.depth {
-            self.y = 0;
-            self.z += 1;
+    pub fn map_in_place(&mut self, mut f: impl FnMut(&mut T)) {
+        for chunk in self.data.chunks_exact_mut(<T>::SIZE as usize) {
+            f(<T>::from_slice_mut(chunk));
         }
-        let (x, y, z) = (self.x, self.y, self.z);
-        self.x += 1;
-        self.chunks.next().map(|t| (x, y, z, <T>::from_slice(t)))
     }
-}
 
+    // Builds a new grid from f applied to every cell, which can sample cells
+    // at an offset from it, None outside the grid
+    pub fn map_with_neighbors(
+        &self,
+        f: impl for<'a> Fn(&'a T, &dyn Fn(i32, i32, i32) -> Option<&'a T>) -> T,
+    ) -> Grid<T> {
+        let mut output = Grid::new(self.width, self.depth, self.height);
+        for ((x, y, z, t), (.., o)) in self.enumerate_cells().zip(output.enumerate_cells_mut()) {
+            let sample = |dx: i32, dy: i32, dz: i32| {
+                let (nx, ny, nz) =
+                    (x.checked_add_signed(dx)?, y.checked_add_signed(dy)?, z.checked_add_signed(dz)?);
+                self.contains(nx, ny, nz).then(|| self.get(nx, ny, nz))
+            };
+            *o = f(t, &sample);
+        }
+        output
+    }
 
-pub struct EnumerateCellsMut<'a, T> {
-    chunks: ChunksExactMut<'a, u8>,
-    x: u32,
-    y: u32,
-    z: u32,
-    width: u32,
-    depth: u32,
-    _phantom: PhantomData<T>,
-}
+    // Sets a on cells of even parity and b on odd ones
+    pub fn fill_checker(&mut self, a: T, b: T) {
+        self.for_each_mut(|x, y, z, t| *t = if parity(x, y, z) { b } else { a });
+    }
 
-impl<'a, T> Iterator for EnumerateCellsMut<'a, T>
-where
-    T: Codec + 'a,
-{
-    type Item = (u32, u32, u32, &'a mut T);
+    // Zeroes the cells failing the predicate, which is transparent for Voxel
+    pub fn retain(&mut self, pred: impl Fn(u32, u32, u32, &T) -> bool) {
+        let zero = *<T>::from_slice(&vec![0; <T>::SIZE as usize]);
+        for (x, y, z, t) in self.enumerate_cells_mut() {
+            if !pred(x, y, z, t) {
+                *t = zero;
+            }
+        }
+    }
 
-    #[inline(always)]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.x >= self.width {
-            self.x = 0;
-            self.y += 1;
+    // Applies f to the cells from min to max inclusive, clamped to the grid
+    pub fn map_region(
+        &mut self,
+        min: (u32, u32, u32),
+        max: (u32, u32, u32),
+        mut f: impl FnMut(u32, u32, u32, &mut T),
+    ) {
+        if self.cell_count() == 0 {
+            return;
         }
-        if self.y >= self.depth {
-            self.y = 0;
-            self.z += 1;
+        let max = (
+            max.0.min(self.width - 1),
+            max.1.min(self.depth - 1),
+            max.2.min(self.height - 1),
+        );
+        for z in min.2..=max.2 {
+            for y in min.1..=max.1 {
+                for x in min.0..=max.0 {
+                    let indices = self.indices_unchecked(self.id_unchecked(x, y, z));
+                    f(x, y, z, <T>::from_slice_mut(&mut self.data[indices]));
+                }
+            }
         }
-        let (x, y, z) = (self.x, self.y, self.z);
-        self.x += 1;
-        self.chunks.next().map(|t| (x, y, z, <T>::from_slice_mut(t)))
     }
-}
 
+    // Sets cells whose coordinate lies within radius of the center
+    pub fn draw_sphere(&mut self, center: [f32; 3], radius: f32, value: T) {
+        self.map_sphere(center, radius, |_, t| *t = value);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-
-    const RED: [u8; 4] = [255, 0, 0, 255];
-    const RED_SLICE: [u8; 8] = [255, 0, 0, 255, 0, 0, 0, 0];
-    const RED_VOXEL: Voxel = Voxel(RED_SLICE);
+    // Sets cells whose distance from the center is in (radius - thickness, radius]
+    pub fn draw_sphere_shell(&mut self, center: [f32; 3], radius: f32, thickness: f32, value: T) {
+        self.map_sphere(center, radius, |distance, t| {
+            if distance > radius - thickness {
+                *t = value;
+            }
+        });
+    }
 
-    #[test]
-    fn test_u32_as_slice() {
-        let value = 1.as_slice();
-        let slice = [1, 0, 0, 0];
-        assert_eq!(*value, slice);
+    // Applies f with the distance to every cell within radius of the center
+    fn map_sphere(&mut self, center: [f32; 3], radius: f32, mut f: impl FnMut(f32, &mut T)) {
+        if radius < 0.0 {
+            return;
+        }
+        let low = |c: f32| (c - radius).ceil().max(0.0) as u32;
+        let high = |c: f32| (c + radius).floor().max(0.0) as u32;
+        if center.iter().any(|c| c + radius < 0.0) {
+            return;
+        }
+        let min = (low(center[0]), low(center[1]), low(center[2]));
+        let max = (high(center[0]), high(center[1]), high(center[2]));
+        self.map_region(min, max, |x, y, z, t| {
+            let d = [x as f32 - center[0], y as f32 - center[1], z as f32 - center[2]];
+            let distance = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+            if distance <= radius {
+                f(distance, t);
+            }
+        });
     }
 
-    #[test]
-    fn test_u32_from_slice() {
-        let slice = [1, 0, 0, 0];
-        let value = <u32>::from_slice(&slice);
-        assert_eq!(*value, 1);
+    pub fn shift_in_place(&mut self, dx: i32, dy: i32, dz: i32, fill: T) {
+        let size = <T>::SIZE as usize;
+        let offset = dx as i64
+            + dy as i64 * self.width as i64
+            + dz as i64 * self.width as i64 * self.depth as i64;
+        // Visit destinations away from their sources so no source cell is
+        // overwritten before it is read
+        let ids: Box<dyn Iterator<Item = usize>> = if offset > 0 {
+            Box::new((0..self.cell_count()).rev())
+        } else {
+            Box::new(0..self.cell_count())
+        };
+        for id in ids {
+            let (x, y, z) = self.coordinate_unchecked(id);
+            let (sx, sy, sz) = (x as i64 - dx as i64, y as i64 - dy as i64, z as i64 - dz as i64);
+            let source = if sx < 0 || sy < 0 || sz < 0 {
+                None
+            } else {
+                self.id(sx as u32, sy as u32, sz as u32)
+            };
+            match source {
+                None => *self.get_id_mut(id) = fill,
+                Some(source) => self.data.copy_within(source * size..(source + 1) * size, id * size),
+            }
+        }
     }
 
-    #[test]
-    fn test_u32_from_slice_mut() {
-        let mut slice = [1, 0, 0, 0];
-        let value = <u32>::from_slice_mut(&mut slice);
-        *value += 1;
-        assert_eq!(*value, 2);
-        assert_eq!(slice, [2, 0, 0, 0]);
+    pub fn with_border(&self, thickness: u32, value: T) -> Grid<T> {
+        let dims = [self.width, self.depth, self.height].map(|n| {
+            match thickness.checked_mul(2).and_then(|border| n.checked_add(border)) {
+                None => panic!("Grid bordered dimension overflows u32"),
+                Some(n) => n,
+            }
+        });
+        let mut output = Grid::new(dims[0], dims[1], dims[2]);
+        output.map_in_place(|t| *t = value);
+        for (x, y, z, t) in self.enumerate_cells() {
+            *output.get_mut(x + thickness, y + thickness, z + thickness) = *t;
+        }
+        output
     }
 
-    #[test]
-    fn test_voxel_as_rgba() {
-        assert_eq!(RED_VOXEL.as_rgba(), RED);
+    pub fn swap_layers(&mut self, z0: u32, z1: u32) {
+        if z0 >= self.height || z1 >= self.height {
+            panic!("Grid layers {:?} out of bounds {}", (z0, z1), self.height);
+        }
+        let (low, high) = (z0.min(z1) as usize, z0.max(z1) as usize);
+        if low == high {
+            return;
+        }
+        let layer_size = self.layer_bytes;
+        let (below, above) = self.data.split_at_mut(high * layer_size);
+        below[low * layer_size..(low + 1) * layer_size].swap_with_slice(&mut above[..layer_size]);
+    }
+
+    // In place equivalent of mirror(Axis::Z)
+    pub fn reverse_z(&mut self) {
+        for z in 0..self.height / 2 {
+            self.swap_layers(z, self.height - 1 - z);
+        }
+    }
+
+    // Appends layers of fill on top. Layers are outermost so existing cells
+    // keep their place in the buffer.
+    pub fn extend_z(&mut self, additional: u32, fill: T) {
+        let height = match self.height.checked_add(additional) {
+            None => panic!("Grid height overflows u32"),
+            Some(height) => height,
+        };
+        let len = match Self::len(self.width, self.depth, height) {
+            None => panic!("Grid len overflows usize"),
+            Some(len) => len,
+        };
+        self.data.reserve(len - self.data.len());
+        while self.data.len() < len {
+            self.data.extend_from_slice(fill.as_slice());
+        }
+        self.height = height;
+    }
+
+    // Drops the layers at and above new_height
+    pub fn truncate_z(&mut self, new_height: u32) {
+        if new_height > self.height {
+            panic!("Grid height {} cannot truncate to {}", self.height, new_height);
+        }
+        self.data.truncate(Self::len(self.width, self.depth, new_height).unwrap());
+        self.height = new_height;
+    }
+
+    // Places the content centered in the new dimensions, cropping evenly from
+    // both sides where they are smaller. Odd remainders go to the high side.
+    pub fn resized_centered(&self, width: u32, depth: u32, height: u32, fill: T) -> Grid<T> {
+        let mut output = Grid::new(width, depth, height);
+        output.map_in_place(|t| *t = fill);
+        let offset = [(width, self.width), (depth, self.depth), (height, self.height)]
+            .map(|(new, old)| (new as i64 - old as i64) / 2);
+        for (x, y, z, t) in self.enumerate_cells() {
+            let p = [x as i64 + offset[0], y as i64 + offset[1], z as i64 + offset[2]];
+            if p.iter().all(|c| *c >= 0) && output.contains(p[0] as u32, p[1] as u32, p[2] as u32) {
+                *output.get_mut(p[0] as u32, p[1] as u32, p[2] as u32) = *t;
+            }
+        }
+        output
+    }
+
+    // Repeats the grid nx, ny and nz times along each axis
+    pub fn tile(&self, nx: u32, ny: u32, nz: u32) -> Grid<T> {
+        let dims = [(self.width, nx), (self.depth, ny), (self.height, nz)].map(|(n, count)| {
+            match n.checked_mul(count) {
+                None => panic!("Grid tiled dimension overflows u32"),
+                Some(n) => n,
+            }
+        });
+        let mut output = Grid::new(dims[0], dims[1], dims[2]);
+        for (x, y, z, t) in output.enumerate_cells_mut() {
+            *t = *self.get(x % self.width, y % self.depth, z % self.height);
+        }
+        output
+    }
+
+    // Resamples to the new dimensions, each output cell taking the source cell
+    // under its center
+    pub fn scale_nearest(&self, new_width: u32, new_depth: u32, new_height: u32) -> Grid<T> {
+        let mut output = Grid::new(new_width, new_depth, new_height);
+        if self.cell_count() == 0 {
+            return output;
+        }
+        let source = |o: u32, from: u32, to: u32| ((2 * o as u64 + 1) * from as u64 / (2 * to as u64)) as u32;
+        for (x, y, z, t) in output.enumerate_cells_mut() {
+            *t = *self.get(
+                source(x, self.width, new_width),
+                source(y, self.depth, new_depth),
+                source(z, self.height, new_height),
+            );
+        }
+        output
+    }
+
+    // Construction checked the byte length fits usize, so the count can
+    // never overflow
+    pub fn cell_count(&self) -> usize {
+        self.data.len() / <T>::SIZE as usize
+    }
+
+    // Shortest face connected path from start to goal through passable cells,
+    // both ends included, found with A* under the Manhattan distance. None
+    // when either end is outside or impassable or no path exists.
+    pub fn find_path(
+        &self,
+        start: (u32, u32, u32),
+        goal: (u32, u32, u32),
+        passable: impl Fn(&T) -> bool,
+    ) -> Option<Vec<(u32, u32, u32)>> {
+        let open = |(x, y, z): (u32, u32, u32)| self.contains(x, y, z) && passable(self.get(x, y, z));
+        if !open(start) || !open(goal) {
+            return None;
+        }
+        let heuristic = |(x, y, z): (u32, u32, u32)| {
+            x.abs_diff(goal.0) as u64 + y.abs_diff(goal.1) as u64 + z.abs_diff(goal.2) as u64
+        };
+        let start_id = self.id_unchecked(start.0, start.1, start.2);
+        // Cost so far and previous cell id of every reached cell
+        let mut reached: HashMap<usize, (u64, usize)> = HashMap::from([(start_id, (0, start_id))]);
+        let mut frontier = BinaryHeap::from([Reverse((heuristic(start), 0, start_id))]);
+        while let Some(Reverse((_, cost, id))) = frontier.pop() {
+            let (x, y, z) = self.coordinate_unchecked(id);
+            if (x, y, z) == goal {
+                let mut path = vec![goal];
+                let mut id = id;
+                while id != start_id {
+                    id = reached[&id].1;
+                    path.push(self.coordinate_unchecked(id));
+                }
+                path.reverse();
+                return Some(path);
+            }
+            if cost > reached[&id].0 {
+                continue;
+            }
+            for neighbor in self.neighbors(x, y, z, Connectivity::Face) {
+                let neighbor_id = self.id_unchecked(neighbor.0, neighbor.1, neighbor.2);
+                let better = reached.get(&neighbor_id).is_none_or(|(c, _)| cost + 1 < *c);
+                if better && open(neighbor) {
+                    reached.insert(neighbor_id, (cost + 1, id));
+                    frontier.push(Reverse((cost + 1 + heuristic(neighbor), cost + 1, neighbor_id)));
+                }
+            }
+        }
+        None
+    }
+
+    // First cell in storage order satisfying the predicate
+    pub fn find(&self, pred: impl Fn(&T) -> bool) -> Option<(u32, u32, u32)> {
+        self.enumerate_cells().find(|(.., t)| pred(t)).map(|(x, y, z, _)| (x, y, z))
+    }
+
+    // Every cell satisfying the predicate, in storage order
+    pub fn positions_of(&self, pred: impl Fn(&T) -> bool) -> Vec<(u32, u32, u32)> {
+        self.enumerate_cells().filter(|(.., t)| pred(t)).map(|(x, y, z, _)| (x, y, z)).collect()
+    }
+
+    // The value shared by every cell, compared bytewise. None when cells
+    // differ or the grid is empty.
+    pub fn is_uniform(&self) -> Option<&T> {
+        let mut chunks = self.data.chunks_exact(<T>::SIZE as usize);
+        let first = chunks.next()?;
+        chunks.all(|chunk| chunk == first).then(|| <T>::from_slice(first))
+    }
+
+    pub fn count_by<K: Eq + Hash>(&self, key: impl Fn(&T) -> K) -> HashMap<K, usize> {
+        let mut counts = HashMap::new();
+        for chunk in self.data.chunks_exact(<T>::SIZE as usize) {
+            *counts.entry(key(<T>::from_slice(chunk))).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn apply_patch(&mut self, patch: &[(u32, u32, u32, T)]) -> Vec<(u32, u32, u32, T)> {
+        let mut inverse = Vec::with_capacity(patch.len());
+        for &(x, y, z, t) in patch {
+            let cell = self.get_mut(x, y, z);
+            inverse.push((x, y, z, *cell));
+            *cell = t;
+        }
+        // Undo in reverse so repeated coordinates restore their earliest value
+        inverse.reverse();
+        inverse
+    }
+
+    // Reuses the buffer as a grid of U. Equal sizes keep the dimensions,
+    // otherwise each row of width * T::SIZE bytes must split evenly into U
+    // cells and the width is recomputed as that row size / U::SIZE, which must
+    // fit in u32. Depth and height are kept. Fails returning self.
+    pub fn reinterpret<U: Codec + Copy>(self) -> Result<Grid<U>, Grid<T>> {
+        let row_size = self.width as usize * <T>::SIZE as usize;
+        if !row_size.is_multiple_of(<U>::SIZE as usize) {
+            return Err(self);
+        }
+        let width = match u32::try_from(row_size / <U>::SIZE as usize) {
+            Err(_) => return Err(self),
+            Ok(width) => width,
+        };
+        Ok(Grid {
+            width,
+            depth: self.depth,
+            height: self.height,
+            layer: width as usize * self.depth as usize,
+            row_bytes: self.row_bytes,
+            layer_bytes: self.layer_bytes,
+            data: self.data,
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn orient(&self, orientation: Orientation) -> Grid<T> {
+        self.transformed(orientation.0)
+    }
+
+    // Reflects the grid across the plane perpendicular to the axis
+    pub fn mirror(&self, axis: Axis) -> Grid<T> {
+        let mut m = Orientation::IDENTITY.0;
+        m[axis as usize][axis as usize] = -1;
+        self.transformed(m)
+    }
+
+    // Rotates counterclockwise looking down the axis from its positive end
+    pub fn rotate(&self, axis: Axis, rotation: &Rotation) -> Grid<T> {
+        self.orient(Orientation::about(axis, rotation))
+    }
+
+    // Rotates a right-handed z-up grid into a right-handed y-up one, so the
+    // height runs along y and +y maps to -z. Cell (x, y, z) moves to
+    // (x, z, depth - 1 - y).
+    pub fn to_y_up(&self) -> Grid<T> {
+        self.transformed([[1, 0, 0], [0, 0, 1], [0, -1, 0]])
+    }
+
+    // Inverse of to_y_up
+    pub fn to_z_up(&self) -> Grid<T> {
+        self.transformed([[1, 0, 0], [0, 0, -1], [0, 1, 0]])
+    }
+
+    // Applies a signed permutation matrix, shifting the result so it starts
+    // at the origin
+    fn transformed(&self, m: [[i32; 3]; 3]) -> Grid<T> {
+        let dims = [self.width, self.depth, self.height];
+        // Source axis and sign feeding each output axis
+        let axes = m.map(|row| {
+            let j = row.iter().position(|m| *m != 0).unwrap();
+            (j, row[j] < 0)
+        });
+        let mut output = Grid::new(dims[axes[0].0], dims[axes[1].0], dims[axes[2].0]);
+        for (x, y, z, t) in self.enumerate_cells() {
+            let p = [x, y, z];
+            let q = axes.map(|(j, flip)| if flip { dims[j] - 1 - p[j] } else { p[j] });
+            *output.get_mut(q[0], q[1], q[2]) = *t;
+        }
+        output
+    }
+
+    // FNV-1a hash of the dimensions and cell bytes, stable across runs
+    pub fn content_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let dims = [self.width, self.depth, self.height].map(u32::to_le_bytes);
+        for byte in dims.iter().flatten().chain(&self.data) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    // Native format: SOLG magic, version, T::SIZE, little endian width, depth
+    // and height, then the cell bytes as stored
+    pub fn write(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(b"SOLG")?;
+        w.write_all(&[NATIVE_VERSION, <T>::SIZE])?;
+        w.write_all(&u32::to_le_bytes(self.width))?;
+        w.write_all(&u32::to_le_bytes(self.depth))?;
+        w.write_all(&u32::to_le_bytes(self.height))?;
+        w.write_all(&self.data)
+    }
+
+    pub fn read(r: &mut impl Read) -> std::io::Result<Grid<T>> {
+        let invalid = |message| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        let mut header = [0; 18];
+        r.read_exact(&mut header)?;
+        if &header[0..4] != b"SOLG" {
+            return Err(invalid("Grid magic missing".to_string()));
+        }
+        if header[4] != NATIVE_VERSION {
+            return Err(invalid(format!("Grid version {} unsupported", header[4])));
+        }
+        if header[5] != <T>::SIZE {
+            return Err(invalid(format!(
+                "Grid cell size {} does not match {}",
+                header[5],
+                <T>::SIZE
+            )));
+        }
+        let dimension = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let (width, depth, height) = (dimension(6), dimension(10), dimension(14));
+        let len = Self::len(width, depth, height)
+            .ok_or_else(|| invalid("Grid len overflows usize".to_string()))?;
+        let mut data = Vec::new();
+        r.take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        let layer = width as usize * depth as usize;
+        let row_bytes = width as usize * <T>::SIZE as usize;
+        let layer_bytes = layer * <T>::SIZE as usize;
+        Ok(Grid {
+            width,
+            depth,
+            height,
+            layer,
+            row_bytes,
+            layer_bytes,
+            data,
+            _phantom: PhantomData,
+        })
+    }
+
+    #[cfg(feature = "flate2")]
+    pub fn write_gz(&self, w: &mut impl Write) -> std::io::Result<()> {
+        let mut encoder = flate2::write::GzEncoder::new(w, flate2::Compression::default());
+        self.write(&mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "flate2")]
+    pub fn read_gz(r: &mut impl Read) -> std::io::Result<Grid<T>> {
+        Self::read(&mut flate2::read::GzDecoder::new(r))
+    }
+
+    // Rotates every z layer counterclockwise in the xy plane, swapping width
+    // and depth for R90 and R270. Heights are untouched.
+    pub fn rotate_xy(&self, rotation: &Rotation) -> Grid<T> {
+        self.orient(Orientation::from(rotation))
+    }
+
+    // Rotates about the z axis, rotating around the grid center for any
+    // dimensions rather than a center cell
+    pub fn rotated_z(&self, rotation: &Rotation) -> Grid<T> {
+        self.orient(Orientation::from(rotation))
+    }
+}
+
+impl<T> Grid<T>
+where
+    T: Codec + Copy + PartialEq,
+{
+    pub fn diff(&self, other: &Grid<T>) -> Vec<(u32, u32, u32, T)> {
+        assert_eq!(
+            (self.width, self.depth, self.height),
+            (other.width, other.depth, other.height),
+            "Grid dimensions differ"
+        );
+        self.enumerate_cells()
+            .zip(other.enumerate_cells())
+            .filter(|((_, _, _, a), (_, _, _, b))| a != b)
+            .map(|(_, (x, y, z, b))| (x, y, z, *b))
+            .collect()
+    }
+
+    // Finds an orientation mapping other onto self
+    pub fn is_rotation_of(&self, other: &Grid<T>) -> Option<Orientation> {
+        Orientation::all()
+            .into_iter()
+            .find(|orientation| other.orient(*orientation) == *self)
+    }
+}
+
+impl<T> Grid<T>
+where
+    T: Codec + Copy + Eq + Hash,
+{
+    pub fn distinct_values(&self) -> usize {
+        self.enumerate_cells().map(|(_, _, _, t)| *t).collect::<HashSet<T>>().len()
+    }
+}
+
+impl Grid<Voxel> {
+    // Orients the prop and places its min corner at `at`, clipped to the
+    // grid. Opaque prop voxels replace the cells below them, or blend over
+    // them when blend is set, and transparent ones leave them untouched.
+    pub fn stamp(
+        &mut self,
+        prop: &Grid<Voxel>,
+        at: (u32, u32, u32),
+        orientation: Orientation,
+        blend: bool,
+    ) {
+        let prop = prop.orient(orientation);
+        for (x, y, z, v) in prop.enumerate_cells() {
+            if v.as_rgba()[3] == 0 {
+                continue;
+            }
+            let p = (x.checked_add(at.0), y.checked_add(at.1), z.checked_add(at.2));
+            if let (Some(px), Some(py), Some(pz)) = p {
+                if self.contains(px, py, pz) {
+                    let cell = self.get_mut(px, py, pz);
+                    *cell = if blend { v.blend_over(cell) } else { *v };
+                }
+            }
+        }
+    }
+
+    // Blends the color over cells within radius with alpha falling off
+    // linearly from full at the center to zero at the radius
+    pub fn add_colored_sphere(&mut self, center: [f32; 3], radius: f32, color: [u8; 4]) {
+        self.map_sphere(center, radius, |distance, v| {
+            let falloff = if radius > 0.0 { 1.0 - distance / radius } else { 1.0 };
+            let alpha = (color[3] as f32 * falloff).round() as u8;
+            if alpha > 0 {
+                *v = Voxel::from([color[0], color[1], color[2], alpha]).blend_over(v);
+            }
+        });
+    }
+
+    // Keeps each opaque voxel with probability density, decided by a hash of
+    // the seed and coordinate, and makes the rest fully transparent
+    pub fn dither_alpha(&mut self, seed: u64, density: f32) {
+        self.for_each_mut(|x, y, z, v| {
+            let sample = (hash(seed, x, y, z) >> 40) as f32 / (1u64 << 24) as f32;
+            if v.0[3] > 0 && sample >= density {
+                v.0[3] = 0;
+            }
+        });
+    }
+
+    // Scales opaque RGB about mid grey by contrast, then offsets it by
+    // brightness as a fraction of full range. Brightness 0 and contrast 1
+    // leave colors unchanged.
+    pub fn adjust(&mut self, brightness: f32, contrast: f32) {
+        self.map_in_place(|v| {
+            if v.0[3] == 0 {
+                return;
+            }
+            for c in &mut v.0[0..3] {
+                let adjusted = (*c as f32 - 127.5) * contrast + 127.5 + brightness * 255.0;
+                *c = adjusted.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    }
+
+    // Trilinearly interpolates the corner colors, indexed by x in bit 0, y in
+    // bit 1 and z in bit 2, so the corner cells take them exactly
+    pub fn fill_gradient(&mut self, corners: [[u8; 4]; 8]) {
+        let dims = [self.width, self.depth, self.height];
+        self.for_each_mut(|x, y, z, v| {
+            let p = [x, y, z];
+            let t: [f32; 3] =
+                std::array::from_fn(|a| if dims[a] > 1 { p[a] as f32 / (dims[a] - 1) as f32 } else { 0.0 });
+            let mut rgba = [0.0f32; 4];
+            for (i, corner) in corners.iter().enumerate() {
+                let weight: f32 = (0..3).map(|a| if i >> a & 1 == 1 { t[a] } else { 1.0 - t[a] }).product();
+                for (c, channel) in rgba.iter_mut().zip(corner) {
+                    *c += weight * *channel as f32;
+                }
+            }
+            *v = Voxel::from_rgba(&rgba.map(|c| c.round().clamp(0.0, 255.0) as u8));
+        });
+    }
+
+    // Parses one layer per z level, one row per line along y and one
+    // character per cell along x. '.' and ' ' are transparent, every other
+    // character must be in the palette.
+    pub fn from_ascii(
+        layers: &[&str],
+        palette: &HashMap<char, [u8; 4]>,
+    ) -> Result<Grid<Voxel>, ParseError> {
+        let rows: Vec<Vec<Vec<char>>> = layers
+            .iter()
+            .map(|layer| layer.lines().map(|row| row.chars().collect()).collect())
+            .collect();
+        let first = rows.first().ok_or(ParseError::NoLayers)?;
+        let (width, depth) = (first.first().map_or(0, Vec::len), first.len());
+        let mut grid = Grid::new(width as u32, depth as u32, rows.len() as u32);
+        for (z, layer) in rows.iter().enumerate() {
+            if layer.len() != depth {
+                return Err(ParseError::RowCount { layer: z, expected: depth, actual: layer.len() });
+            }
+            for (y, row) in layer.iter().enumerate() {
+                if row.len() != width {
+                    let actual = row.len();
+                    return Err(ParseError::RowLength { layer: z, row: y, expected: width, actual });
+                }
+                for (x, c) in row.iter().enumerate() {
+                    let rgba = match (c, palette.get(c)) {
+                        ('.' | ' ', _) => continue,
+                        (_, Some(rgba)) => rgba,
+                        (_, None) => {
+                            return Err(ParseError::UnknownChar { layer: z, row: y, column: x, char: *c })
+                        },
+                    };
+                    *grid.get_mut(x as u32, y as u32, z as u32) = Voxel::from(*rgba);
+                }
+            }
+        }
+        Ok(grid)
+    }
+
+    // Fills each column from z = 0 to below its height, in rows of width
+    // along y, with the grid as tall as the highest column
+    pub fn from_heightmap(
+        heights: &[u16],
+        width: u32,
+        depth: u32,
+        color_at: impl Fn(u32, u32, u32) -> Voxel,
+    ) -> Grid<Voxel> {
+        if heights.len() != width as usize * depth as usize {
+            panic!("Grid heightmap of {} heights does not match {:?}", heights.len(), (width, depth));
+        }
+        let height = heights.iter().max().copied().unwrap_or(0) as u32;
+        let mut grid = Grid::new(width, depth, height);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            if z < heights[x as usize + y as usize * width as usize] as u32 {
+                *v = color_at(x, y, z);
+            }
+        }
+        grid
+    }
+
+    // Builds a single layer from pixels in rows of width along y
+    pub fn from_image_layer(pixels: &[[u8; 4]], width: u32, depth: u32) -> Grid<Voxel> {
+        if pixels.len() != width as usize * depth as usize {
+            panic!("Grid image of {} pixels does not match {:?}", pixels.len(), (width, depth));
+        }
+        let mut grid = Grid::new(width, depth, 1);
+        for ((.., v), pixel) in grid.enumerate_cells_mut().zip(pixels) {
+            *v = Voxel::from(*pixel);
+        }
+        grid
+    }
+
+    // Colors of the cells on the +X, -X, +Y, -Y, +Z and -Z faces, each in
+    // storage order
+    pub fn face_signatures(&self) -> [Vec<[u8; 4]>; 6] {
+        let last = [self.width, self.depth, self.height].map(|n| n.saturating_sub(1));
+        let mut faces: [Vec<[u8; 4]>; 6] = Default::default();
+        for (x, y, z, v) in self.enumerate_cells() {
+            let color: [u8; 4] = (*v).into();
+            for (axis, c) in [x, y, z].into_iter().enumerate() {
+                if c == last[axis] {
+                    faces[2 * axis].push(color);
+                }
+                if c == 0 {
+                    faces[2 * axis + 1].push(color);
+                }
+            }
+        }
+        faces
+    }
+
+    // Fills the transparent cells sharing a face with an opaque voxel with
+    // the color, keeping every opaque voxel as is
+    pub fn outline(&self, color: [u8; 4]) -> Grid<Voxel> {
+        let mut output = self.clone();
+        for (x, y, z, v) in self.enumerate_cells() {
+            if v.as_rgba()[3] == 0
+                && self.neighbors(x, y, z, Connectivity::Face).any(|(nx, ny, nz)| self.get(nx, ny, nz).as_rgba()[3] > 0)
+            {
+                *output.get_mut(x, y, z) = Voxel::from(color);
+            }
+        }
+        output
+    }
+
+    // Faces of opaque voxels bordering transparent cells or the outside of
+    // the grid, counted toward +X, -X, +Y, -Y, +Z and -Z
+    pub fn exposed_face_counts(&self) -> [usize; 6] {
+        let mut counts = [0; 6];
+        for (x, y, z, v) in self.enumerate_cells() {
+            if v.as_rgba()[3] == 0 {
+                continue;
+            }
+            for (i, count) in counts.iter_mut().enumerate() {
+                let mut p = [x, y, z];
+                let neighbor = if i % 2 == 0 { p[i / 2].checked_add(1) } else { p[i / 2].checked_sub(1) };
+                let covered = neighbor.is_some_and(|n| {
+                    p[i / 2] = n;
+                    self.contains(p[0], p[1], p[2]) && self.get(p[0], p[1], p[2]).as_rgba()[3] > 0
+                });
+                if !covered {
+                    *count += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    // Makes the grid equal to its mirror across x by copying one half of
+    // each mirrored pair of cells onto the other
+    pub fn symmetrize_x(&mut self, prefer: SymPref) {
+        for z in 0..self.height {
+            for y in 0..self.depth {
+                for x in 0..self.width / 2 {
+                    let (low, high) = (*self.get(x, y, z), *self.get(self.width - 1 - x, y, z));
+                    let value = match prefer {
+                        SymPref::KeepLow => low,
+                        SymPref::KeepHigh => high,
+                        SymPref::Union if low.as_rgba()[3] == 0 => high,
+                        SymPref::Union => low,
+                    };
+                    *self.get_mut(x, y, z) = value;
+                    *self.get_mut(self.width - 1 - x, y, z) = value;
+                }
+            }
+        }
+    }
+
+    // Center and color of every opaque voxel, in grid units
+    pub fn to_point_cloud(&self) -> Vec<([f32; 3], [u8; 4])> {
+        self.to_point_cloud_scaled(1.0)
+    }
+
+    pub fn to_point_cloud_scaled(&self, voxel_size: f32) -> Vec<([f32; 3], [u8; 4])> {
+        self.enumerate_cells()
+            .filter(|(.., v)| v.as_rgba()[3] > 0)
+            .map(|(x, y, z, v)| ([x, y, z].map(|c| (c as f32 + 0.5) * voxel_size), (*v).into()))
+            .collect()
+    }
+
+    // Opaque voxels on the first or last layer of any axis
+    pub fn boundary(&self) -> Vec<(u32, u32, u32)> {
+        let last = [self.width, self.depth, self.height].map(|n| n.saturating_sub(1));
+        self.enumerate_cells()
+            .filter(|(x, y, z, v)| {
+                v.as_rgba()[3] > 0 && [*x, *y, *z].iter().zip(last).any(|(c, last)| *c == 0 || *c == last)
+            })
+            .map(|(x, y, z, _)| (x, y, z))
+            .collect()
+    }
+
+    // Marks cells opaque only in other as added, opaque only in self as
+    // removed and opaque in both with different RGBA as changed. Everything
+    // else is transparent.
+    pub fn diff_color(
+        &self,
+        other: &Grid<Voxel>,
+        added: [u8; 4],
+        removed: [u8; 4],
+        changed: [u8; 4],
+    ) -> Grid<Voxel> {
+        let dims = (self.width, self.depth, self.height);
+        if dims != (other.width, other.depth, other.height) {
+            panic!(
+                "Grid dimensions {:?} differ from {:?}",
+                dims,
+                (other.width, other.depth, other.height)
+            );
+        }
+        let mut output = Grid::new(self.width, self.depth, self.height);
+        let cells = self.enumerate_cells().zip(other.enumerate_cells());
+        for (((.., a), (.., b)), (.., o)) in cells.zip(output.enumerate_cells_mut()) {
+            let (a, b) = (a.as_rgba(), b.as_rgba());
+            let color = match (a[3] > 0, b[3] > 0) {
+                (false, true) => added,
+                (true, false) => removed,
+                (true, true) if a != b => changed,
+                _ => continue,
+            };
+            *o = Voxel::from(color);
+        }
+        output
+    }
+
+    // Whether every cell is opaque
+    pub fn is_solid(&self) -> bool {
+        self.enumerate_cells().all(|(.., v)| v.as_rgba()[3] > 0)
+    }
+
+    // Equal dimensions and RGBA of opaque voxels, with any two transparent
+    // voxels treated as equal
+    pub fn visually_eq(&self, other: &Grid<Voxel>) -> bool {
+        (self.width, self.depth, self.height) == (other.width, other.depth, other.height)
+            && self.enumerate_cells().zip(other.enumerate_cells()).all(|((.., a), (.., b))| {
+                let (a, b) = (a.as_rgba(), b.as_rgba());
+                (a[3] == 0 && b[3] == 0) || a == b
+            })
+    }
+
+    // Unique RGBA values, ignoring the bytes after RGBA
+    pub fn distinct_colors(&self) -> usize {
+        self.enumerate_cells().map(|(_, _, _, v)| v.as_rgba()).collect::<HashSet<&[u8]>>().len()
+    }
+
+    // Heightmap terrain from seeded value noise, with the topmost voxel of each
+    // column in the surface color
+    pub fn terrain(
+        width: u32,
+        depth: u32,
+        height: u32,
+        seed: u64,
+        surface_color: Voxel,
+        below_color: Voxel,
+    ) -> Grid<Voxel> {
+        const CELL: f32 = 8.0;
+        let lattice = |x: u32, y: u32| (hash(seed, x, y, 0) % 1024) as f32 / 1023.0;
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+        let mut grid = Grid::new(width, depth, height);
+        for x in 0..width {
+            for y in 0..depth {
+                let (fx, fy) = (x as f32 / CELL, y as f32 / CELL);
+                let (x0, y0) = (fx as u32, fy as u32);
+                let (tx, ty) = (smooth(fx.fract()), smooth(fy.fract()));
+                let top = lattice(x0, y0) + (lattice(x0 + 1, y0) - lattice(x0, y0)) * tx;
+                let bottom = lattice(x0, y0 + 1) + (lattice(x0 + 1, y0 + 1) - lattice(x0, y0 + 1)) * tx;
+                let noise = top + (bottom - top) * ty;
+                let surface = (noise * height.saturating_sub(1) as f32).round() as u32;
+                for z in 0..height.min(surface + 1) {
+                    *grid.get_mut(x, y, z) = if z == surface { surface_color } else { below_color };
+                }
+            }
+        }
+        grid
+    }
+
+    // Clears every opaque voxel outside the largest face connected group,
+    // keeping the first labeled group on ties
+    pub fn keep_largest_component(&self) -> Grid<Voxel> {
+        let (labels, count) = self.label_components(Connectivity::Face);
+        let mut sizes = vec![0usize; count as usize + 1];
+        for (_, _, _, label) in labels.enumerate_cells() {
+            sizes[*label as usize] += 1;
+        }
+        let largest = (1..sizes.len()).rev().max_by_key(|label| sizes[*label]).unwrap_or(0) as u32;
+        let mut output = self.clone();
+        for ((_, _, _, v), (_, _, _, label)) in output.enumerate_cells_mut().zip(labels.enumerate_cells()) {
+            if *label != largest {
+                *v = Voxel([0; 8]);
+            }
+        }
+        output
+    }
+
+    // Every opaque voxel the ray enters within max_dist, in traversal order.
+    // Voxel (x, y, z) spans [x, x + 1) on each axis.
+    pub fn raycast_all(&self, origin: [f32; 3], dir: [f32; 3], max_dist: f32) -> Vec<(u32, u32, u32)> {
+        self.traverse(origin, dir, max_dist)
+            .into_iter()
+            .filter(|(x, y, z)| self.get(*x, *y, *z).as_rgba()[3] > 0)
+            .collect()
+    }
+
+    // Cells crossed by a ray using Amanatides and Woo's DDA traversal
+    fn traverse(&self, origin: [f32; 3], dir: [f32; 3], max_dist: f32) -> Vec<(u32, u32, u32)> {
+        let mut cells = Vec::new();
+        let length = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+        if length == 0.0 || self.cell_count() == 0 {
+            return cells;
+        }
+        let d = dir.map(|c| c / length);
+        let dims = [self.width as f32, self.depth as f32, self.height as f32];
+        // Clip the ray to the grid bounds
+        let (mut t_enter, mut t_exit) = (0.0f32, max_dist);
+        for i in 0..3 {
+            if d[i] == 0.0 {
+                if origin[i] < 0.0 || origin[i] >= dims[i] {
+                    return cells;
+                }
+            } else {
+                let a = (0.0 - origin[i]) / d[i];
+                let b = (dims[i] - origin[i]) / d[i];
+                t_enter = t_enter.max(a.min(b));
+                t_exit = t_exit.min(a.max(b));
+            }
+        }
+        if t_enter > t_exit {
+            return cells;
+        }
+        let bounds = [self.width, self.depth, self.height];
+        let mut cell = [0i64; 3];
+        let mut step = [0i64; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for i in 0..3 {
+            let p = origin[i] + d[i] * t_enter;
+            cell[i] = (p.floor() as i64).clamp(0, bounds[i] as i64 - 1);
+            if d[i] > 0.0 {
+                step[i] = 1;
+                t_max[i] = t_enter + (cell[i] as f32 + 1.0 - p) / d[i];
+                t_delta[i] = 1.0 / d[i];
+            } else if d[i] < 0.0 {
+                step[i] = -1;
+                t_max[i] = t_enter + (cell[i] as f32 - p) / d[i];
+                t_delta[i] = -1.0 / d[i];
+            }
+        }
+        loop {
+            cells.push((cell[0] as u32, cell[1] as u32, cell[2] as u32));
+            let axis = (0..3).min_by(|a, b| t_max[*a].total_cmp(&t_max[*b])).unwrap();
+            if t_max[axis] > t_exit {
+                break;
+            }
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= bounds[axis] as i64 {
+                break;
+            }
+            t_max[axis] += t_delta[axis];
+        }
+        cells
+    }
+
+    // Labels each connected group of opaque voxels 1, 2, ... in storage order
+    // of their first voxel, returning the labels and the group count
+    pub fn label_components(&self, connectivity: Connectivity) -> (Grid<u32>, u32) {
+        let mut labels = Grid::new(self.width, self.depth, self.height);
+        let mut count = 0;
+        let mut queue = VecDeque::new();
+        for (x, y, z, v) in self.enumerate_cells() {
+            if v.as_rgba()[3] == 0 || *labels.get(x, y, z) != 0 {
+                continue;
+            }
+            count += 1;
+            *labels.get_mut(x, y, z) = count;
+            queue.push_back((x, y, z));
+            while let Some((x, y, z)) = queue.pop_front() {
+                for (nx, ny, nz) in self.neighbors(x, y, z, connectivity) {
+                    if *labels.get(nx, ny, nz) == 0 && self.get(nx, ny, nz).as_rgba()[3] > 0 {
+                        *labels.get_mut(nx, ny, nz) = count;
+                        queue.push_back((nx, ny, nz));
+                    }
+                }
+            }
+        }
+        (labels, count)
+    }
+
+    // Marks each block^3 region holding any opaque voxel with 1, rounding the
+    // output dimensions up so partial blocks at the far edges are included.
+    // The result keeps a byte per cell; bitgrid::BitGrid::from(&occupancy)
+    // packs it into one bit per cell.
+    pub fn occupancy_bitset(&self, block: u32) -> Grid<u8> {
+        assert!(block > 0, "Grid block size must be positive");
+        let mut occupancy = Grid::new(
+            self.width.div_ceil(block),
+            self.depth.div_ceil(block),
+            self.height.div_ceil(block),
+        );
+        for (x, y, z, v) in self.enumerate_cells() {
+            if v.as_rgba()[3] > 0 {
+                *occupancy.get_mut(x / block, y / block, z / block) = 1;
+            }
+        }
+        occupancy
+    }
+
+    // Lambert shading with dir pointing toward the light. Each voxel's normal
+    // is the average of its exposed faces, enclosed voxels only get ambient.
+    pub fn apply_directional_light(&self, dir: [f32; 3], ambient: f32) -> Grid<Voxel> {
+        let length = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt().max(f32::EPSILON);
+        let light = dir.map(|c| c / length);
+        let mut output = self.clone();
+        for (x, y, z, v) in output.enumerate_cells_mut() {
+            if v.as_rgba()[3] == 0 {
+                continue;
+            }
+            let mut normal = [0.0f32; 3];
+            for (axis, sign) in [(0, -1), (0, 1), (1, -1), (1, 1), (2, -1), (2, 1)] {
+                let mut p = [x as i64, y as i64, z as i64];
+                p[axis] += sign;
+                let exposed = p.iter().any(|c| *c < 0)
+                    || !self.contains(p[0] as u32, p[1] as u32, p[2] as u32)
+                    || self.get(p[0] as u32, p[1] as u32, p[2] as u32).as_rgba()[3] == 0;
+                if exposed {
+                    normal[axis] += sign as f32;
+                }
+            }
+            let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+            let diffuse = if length == 0.0 {
+                0.0
+            } else {
+                ((normal[0] * light[0] + normal[1] * light[1] + normal[2] * light[2]) / length).max(0.0)
+            };
+            let shade = (ambient + diffuse).clamp(0.0, 1.0);
+            for c in &mut v.0[0..3] {
+                *c = (*c as f32 * shade).round() as u8;
+            }
+        }
+        output
+    }
+
+    pub fn color_mask(&self, color: [u8; 4]) -> Grid<u8> {
+        let mut mask = Grid::new(self.width, self.depth, self.height);
+        for ((_, _, _, v), (_, _, _, m)) in self.enumerate_cells().zip(mask.enumerate_cells_mut()) {
+            *m = u8::from(v.as_rgba() == color);
+        }
+        mask
+    }
+
+    // Sets the face connected region from (x, y, z) of cells whose RGB is
+    // within tolerance, by Euclidean distance, of the starting cell's
+    pub fn flood_fill_tolerant(&mut self, x: u32, y: u32, z: u32, new: Voxel, tolerance: u8) {
+        self.flood_tolerant(x, y, z, tolerance, |v| *v = new);
+    }
+
+    // Like flood_fill_tolerant but only replaces RGB, keeping each cell's
+    // alpha and the bytes after it
+    pub fn flood_recolor(&mut self, x: u32, y: u32, z: u32, rgb: [u8; 3], tolerance: u8) {
+        self.flood_tolerant(x, y, z, tolerance, |v| v.0[0..3].copy_from_slice(&rgb));
+    }
+
+    fn flood_tolerant(
+        &mut self,
+        x: u32,
+        y: u32,
+        z: u32,
+        tolerance: u8,
+        mut f: impl FnMut(&mut Voxel),
+    ) {
+        let start: [u8; 4] = (*self.get(x, y, z)).into();
+        let within = |v: &Voxel| {
+            let distance: u32 = (0..3).map(|i| (v.0[i].abs_diff(start[i]) as u32).pow(2)).sum();
+            distance <= (tolerance as u32).pow(2)
+        };
+        let mut visited: Grid<u8> = Grid::new(self.width, self.depth, self.height);
+        let mut queue = VecDeque::from([(x, y, z)]);
+        *visited.get_mut(x, y, z) = 1;
+        while let Some((x, y, z)) = queue.pop_front() {
+            for (nx, ny, nz) in self.neighbors(x, y, z, Connectivity::Face) {
+                if *visited.get(nx, ny, nz) == 0 && within(self.get(nx, ny, nz)) {
+                    *visited.get_mut(nx, ny, nz) = 1;
+                    queue.push_back((nx, ny, nz));
+                }
+            }
+            f(self.get_mut(x, y, z));
+        }
+    }
+
+    // Marks transparent cells reachable from the grid faces through other
+    // transparent cells with 1, leaving enclosed cavities and voxels at 0
+    pub fn exterior_mask(&self) -> Grid<u8> {
+        let mut mask = Grid::new(self.width, self.depth, self.height);
+        let mut queue = VecDeque::new();
+        for (x, y, z, v) in self.enumerate_cells() {
+            let boundary = x == 0
+                || y == 0
+                || z == 0
+                || x == self.width - 1
+                || y == self.depth - 1
+                || z == self.height - 1;
+            if boundary && v.as_rgba()[3] == 0 {
+                *mask.get_mut(x, y, z) = 1;
+                queue.push_back((x, y, z));
+            }
+        }
+        while let Some((x, y, z)) = queue.pop_front() {
+            for (nx, ny, nz) in self.neighbors(x, y, z, Connectivity::Face) {
+                if *mask.get(nx, ny, nz) == 0 && self.get(nx, ny, nz).as_rgba()[3] == 0 {
+                    *mask.get_mut(nx, ny, nz) = 1;
+                    queue.push_back((nx, ny, nz));
+                }
+            }
+        }
+        mask
+    }
+
+    // Average coordinate of the opaque voxels, NaN when there are none
+    pub fn center_of_mass(&self) -> [f32; 3] {
+        let mut sum = [0.0; 3];
+        let mut count = 0;
+        for (x, y, z, v) in self.enumerate_cells() {
+            if v.as_rgba()[3] > 0 {
+                sum[0] += x as f64;
+                sum[1] += y as f64;
+                sum[2] += z as f64;
+                count += 1;
+            }
+        }
+        sum.map(|s| (s / count as f64) as f32)
+    }
+
+    pub fn trim(&self) -> Option<Grid<Voxel>> {
+        self.trim_with_offset().map(|(grid, _)| grid)
+    }
+
+    // Inclusive min and max corners of the cells with exactly this color
+    pub fn bounding_box_of_color(&self, color: [u8; 4]) -> Option<Bounds> {
+        let (min, max) = self.bounds_where(|v| v.as_rgba() == color)?;
+        Some(((min[0], min[1], min[2]), (max[0], max[1], max[2])))
+    }
+
+    fn bounds_where(&self, pred: impl Fn(&Voxel) -> bool) -> Option<([u32; 3], [u32; 3])> {
+        let mut bounds: Option<([u32; 3], [u32; 3])> = None;
+        for (x, y, z, v) in self.enumerate_cells() {
+            if !pred(v) {
+                continue;
+            }
+            let (min, max) = bounds.get_or_insert(([x, y, z], [x, y, z]));
+            for (i, c) in [x, y, z].into_iter().enumerate() {
+                min[i] = min[i].min(c);
+                max[i] = max[i].max(c);
+            }
+        }
+        bounds
+    }
+
+    // Crops to the bounds of the opaque voxels, also returning the min corner
+    // the crop was taken from
+    pub fn trim_with_offset(&self) -> Option<(Grid<Voxel>, (u32, u32, u32))> {
+        let (min, max) = self.bounds_where(|v| v.as_rgba()[3] > 0)?;
+        let mut output = Grid::new(max[0] - min[0] + 1, max[1] - min[1] + 1, max[2] - min[2] + 1);
+        for (x, y, z, v) in output.enumerate_cells_mut() {
+            *v = *self.get(x + min[0], y + min[1], z + min[2]);
+        }
+        Some((output, (min[0], min[1], min[2])))
+    }
+}
+
+impl Grid<u8> {
+    // Treats cells as palette indices, replacing each index i with mapping[i]
+    pub fn remap_palette(&mut self, mapping: &[u8; 256]) {
+        for index in self.data.iter_mut() {
+            *index = mapping[*index as usize];
+        }
+    }
+
+    // Treats cells as palette indices and colors each with palette[i]. The
+    // colors can't be stored back in a u8 cell, so this returns a new grid.
+    pub fn remap_palette_rgba(&self, palette: &[[u8; 4]; 256]) -> Grid<Voxel> {
+        let mut output = Grid::new(self.width, self.depth, self.height);
+        for (index, (_, _, _, v)) in self.data.iter().zip(output.enumerate_cells_mut()) {
+            *v = Voxel::from(palette[*index as usize]);
+        }
+        output
+    }
+
+    // Histogram equalization mapping each value through the cumulative count
+    // of values up to it, stretched so the lowest value present becomes 0 and
+    // the highest 255. Uniform grids are unchanged.
+    pub fn equalize(&mut self) {
+        let mut cumulative = [0usize; 256];
+        for v in &self.data {
+            cumulative[*v as usize] += 1;
+        }
+        for i in 1..256 {
+            cumulative[i] += cumulative[i - 1];
+        }
+        let total = self.data.len();
+        let lowest = match cumulative.iter().find(|c| **c > 0) {
+            Some(lowest) if *lowest < total => *lowest,
+            _ => return,
+        };
+        let mapping: [u8; 256] = std::array::from_fn(|i| {
+            let scaled = cumulative[i].saturating_sub(lowest) as f64 / (total - lowest) as f64;
+            (scaled * 255.0).round() as u8
+        });
+        self.remap_palette(&mapping);
+    }
+
+    // Interpolates the 8 cells around a point, clamping coordinates to the grid.
+    // An empty grid samples as 0.
+    pub fn sample_trilinear(&self, x: f32, y: f32, z: f32) -> f32 {
+        if self.cell_count() == 0 {
+            return 0.0;
+        }
+        let axis = |c: f32, bound: u32| {
+            let c = c.clamp(0.0, (bound - 1) as f32);
+            let c0 = c.floor() as u32;
+            (c0, (c0 + 1).min(bound - 1), c - c0 as f32)
+        };
+        let (x0, x1, tx) = axis(x, self.width);
+        let (y0, y1, ty) = axis(y, self.depth);
+        let (z0, z1, tz) = axis(z, self.height);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let at = |x, y, z| *self.get(x, y, z) as f32;
+        let plane = |z| {
+            lerp(
+                lerp(at(x0, y0, z), at(x1, y0, z), tx),
+                lerp(at(x0, y1, z), at(x1, y1, z), tx),
+                ty,
+            )
+        };
+        lerp(plane(z0), plane(z1), tz)
+    }
+}
+
+// Whether x + y + z is odd
+pub fn parity(x: u32, y: u32, z: u32) -> bool {
+    (x ^ y ^ z) & 1 == 1
+}
+
+// Deterministic SplitMix64 style hash of a seed and coordinate
+fn hash(seed: u64, x: u32, y: u32, z: u32) -> u64 {
+    let mut h = seed ^ ((x as u64) << 42 | (y as u64) << 21 | z as u64);
+    h = h.wrapping_add(0x9e3779b97f4a7c15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+pub struct EnumerateCells<'a, T> {
+    chunks: ChunksExact<'a, u8>,
+    x: u32,
+    y: u32,
+    z: u32,
+    width: u32,
+    depth: u32,
+    _phantom: PhantomData<T>,
+}
+
+impl<'a, T> Iterator for EnumerateCells<'a, T>
+where
+    T: Codec + 'a,
+{
+    type Item = (u32, u32, u32, &'a T);
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+        if self.y >= self.depth {
+            self.y = 0;
+            self.z += 1;
+        }
+        let (x, y, z) = (self.x, self.y, self.z);
+        self.x += 1;
+        self.chunks.next().map(|t| (x, y, z, <T>::from_slice(t)))
+    }
+}
+
+
+pub struct EnumerateCellsMut<'a, T> {
+    chunks: ChunksExactMut<'a, u8>,
+    x: u32,
+    y: u32,
+    z: u32,
+    width: u32,
+    depth: u32,
+    _phantom: PhantomData<T>,
+}
+
+impl<'a, T> Iterator for EnumerateCellsMut<'a, T>
+where
+    T: Codec + 'a,
+{
+    type Item = (u32, u32, u32, &'a mut T);
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += 1;
+        }
+        if self.y >= self.depth {
+            self.y = 0;
+            self.z += 1;
+        }
+        let (x, y, z) = (self.x, self.y, self.z);
+        self.x += 1;
+        self.chunks.next().map(|t| (x, y, z, <T>::from_slice_mut(t)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const RED_SLICE: [u8; 8] = [255, 0, 0, 255, 0, 0, 0, 0];
+    const RED_VOXEL: Voxel = Voxel(RED_SLICE);
+
+    #[test]
+    fn test_u32_as_slice() {
+        let value = 1.as_slice();
+        let slice = [1, 0, 0, 0];
+        assert_eq!(*value, slice);
+    }
+
+    #[test]
+    fn test_u32_from_slice() {
+        let slice = [1, 0, 0, 0];
+        let value = <u32>::from_slice(&slice);
+        assert_eq!(*value, 1);
+    }
+
+    #[test]
+    fn test_u32_from_slice_mut() {
+        let mut slice = [1, 0, 0, 0];
+        let value = <u32>::from_slice_mut(&mut slice);
+        *value += 1;
+        assert_eq!(*value, 2);
+        assert_eq!(slice, [2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_voxel_as_rgba() {
+        assert_eq!(RED_VOXEL.as_rgba(), RED);
+    }
+
+    #[test]
+    fn test_voxel_from_rgba() {
+        assert_eq!(Voxel::from_rgba(&RED), RED_VOXEL);
+    }
+
+    #[test]
+    fn test_voxel_from_array() {
+        let voxel: Voxel = RED.into();
+        assert_eq!(voxel, RED_VOXEL);
+        let rgba: [u8; 4] = voxel.into();
+        assert_eq!(rgba, RED);
+    }
+
+    #[test]
+    fn test_voxel_as_slice() {
+        assert_eq!(RED_VOXEL.as_slice(), RED_SLICE);
+    }
+
+    #[test]
+    fn test_voxel_from_slice() {
+        assert_eq!(*<Voxel>::from_slice(&RED_SLICE), RED_VOXEL);
+    }
+
+    #[test]
+    fn test_voxel_from_slice_mut() {
+        let mut array = [0u8; 8];
+        array.copy_from_slice(&RED_SLICE);
+        let voxel = <Voxel>::from_slice_mut(&mut array);
+        voxel.0[1] = 255;
+        assert_eq!(*voxel, Voxel([255, 255, 0, 255, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_voxel_average() {
+        let black = Voxel::from_rgba(&[0, 0, 0, 255]);
+        let white = Voxel::from_rgba(&[255, 255, 255, 255]);
+        let srgb = Voxel::average(&[black, white], ColorSpace::Srgb);
+        assert_eq!(srgb.as_rgba(), [128, 128, 128, 255]);
+        let linear = Voxel::average(&[black, white], ColorSpace::Linear);
+        assert_eq!(linear.as_rgba(), [188, 188, 188, 255]);
+    }
+
+    #[test]
+    fn test_voxel_emissive() {
+        let mut voxel = RED_VOXEL;
+        assert!(!voxel.is_emissive());
+        voxel.set_emissive(true);
+        assert!(voxel.is_emissive());
+        assert_eq!(voxel.as_rgba(), RED);
+        voxel.set_emissive(false);
+        assert_eq!(voxel, RED_VOXEL);
+    }
+
+    #[test]
+    fn test_voxel_premultiply() {
+        let white = Voxel::from_rgba(&[255, 255, 255, 128]);
+        let premultiplied = white.premultiply();
+        assert_eq!(premultiplied.as_rgba(), [128, 128, 128, 128]);
+        assert_eq!(premultiplied.unpremultiply(), white);
+        assert_eq!(Voxel::from_rgba(&[9, 9, 9, 0]).premultiply().as_rgba(), [0; 4]);
+    }
+
+    #[test]
+    fn test_voxel_blend_over() {
+        let half_red = Voxel::from_rgba(&[255, 0, 0, 128]);
+        let blue = Voxel::from_rgba(&[0, 0, 255, 255]);
+        assert_eq!(half_red.blend_over(&blue).as_rgba(), [128, 0, 127, 255]);
+        // A transparent source leaves the destination color unchanged
+        let clear = Voxel::from_rgba(&[0, 0, 0, 0]);
+        assert_eq!(clear.blend_over(&blue).as_rgba(), blue.as_rgba());
+        assert_eq!(half_red.blend_over(&clear).as_rgba(), half_red.as_rgba());
+    }
+
+    #[test]
+    fn test_grid() {
+        let grid_width = 3;
+        let grid_depth = 3;
+        let grid_height = 3;
+        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+
+        let mut order = 0;
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    order += 1;
+                    *grid.get_mut(x, y, z) = order;
+                }
+            }
+        }
+        order = 0;
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    order += 1;
+                    let value = grid.get(x, y, z);
+                    assert_eq!(*value, order);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_grid_voxel() {
+        let grid_width = 3;
+        let grid_depth = 3;
+        let grid_height = 3;
+        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+
+        let red = [255, 0, 0, 255];
+        let voxel = Voxel::from_rgba(&red);
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    *grid.get_mut(x, y, z) = voxel;
+                }
+            }
+        }
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    let value = grid.get(x, y, z);
+                    assert_eq!(value.as_rgba(), red);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_grid_enumerate_cells() {
+        let grid_width = 3;
+        let grid_depth = 3; 
+        let grid_height = 3;
+        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    let color = [x as u8, y as u8, z as u8, 255];
+                    let voxel = Voxel::from_rgba(&color);
+                    *grid.get_mut(x, y, z) = voxel;
+                }
+            }
+        }
+        for (x, y, z, v) in grid.enumerate_cells() {
+            let rgba = v.as_rgba();
+            assert_eq!(x as u8, rgba[0]);
+            assert_eq!(y as u8, rgba[1]);
+            assert_eq!(z as u8, rgba[2]);
+        }
+    }
+
+    #[test]
+    fn test_grid_enumerate_cells_with_ids() {
+        let grid_width = 3;
+        let grid_depth = 3; 
+        let grid_height = 3;
+        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    let color = [x as u8, y as u8, z as u8, 255];
+                    let voxel = Voxel::from_rgba(&color);
+                    *grid.get_mut(x, y, z) = voxel;
+                }
+            }
+        }
+        for (id, (x, y, z, v))in grid.enumerate_cells().enumerate() {
+            let rgba = v.as_rgba();
+            println!("{:?} {:?}", id, rgba);
+            assert_eq!(x as u8, rgba[0]);
+            assert_eq!(y as u8, rgba[1]);
+            assert_eq!(z as u8, rgba[2]);
+        }
+    }
+
+    #[test]
+    fn test_grid_enumerate_cells_in_z() {
+        let mut grid = Grid::<u32>::new(3, 3, 3);
+        for (_, _, z, t) in grid.enumerate_cells_mut() {
+            *t = z;
+        }
+        let cells: Vec<_> = grid.enumerate_cells_in_z(1..2).collect();
+        assert_eq!(cells.len(), 9);
+        assert_eq!((cells[0].0, cells[0].1, cells[0].2), (0, 0, 1));
+        assert_eq!((cells[8].0, cells[8].1, cells[8].2), (2, 2, 1));
+        assert!(cells.iter().all(|(_, _, z, t)| *z == 1 && **t == 1));
+        assert_eq!(grid.enumerate_cells_in_z(2..10).count(), 9);
+    }
+
+    #[test]
+    fn test_grid_enumerate_morton() {
+        let grid = Grid::<u8>::new(2, 2, 2);
+        let order: Vec<_> = grid.enumerate_morton().map(|(x, y, z, _)| (x, y, z)).collect();
+        assert_eq!(
+            order,
+            vec![
+                (0, 0, 0),
+                (1, 0, 0),
+                (0, 1, 0),
+                (1, 1, 0),
+                (0, 0, 1),
+                (1, 0, 1),
+                (0, 1, 1),
+                (1, 1, 1),
+            ]
+        );
+        let grid = Grid::<u8>::new(3, 2, 1);
+        let order: Vec<_> = grid.enumerate_morton().map(|(x, y, z, _)| (x, y, z)).collect();
+        assert_eq!(order, vec![(0, 0, 0), (1, 0, 0), (0, 1, 0), (1, 1, 0), (2, 0, 0), (2, 1, 0)]);
+        // A flat grid must not walk the whole 2^30 code cube around it
+        let grid = Grid::<u8>::new(1000, 1, 1);
+        let xs: Vec<_> = grid.enumerate_morton().map(|(x, _, _, _)| x).collect();
+        assert_eq!(xs, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_grid_cell_count() {
+        let grid_width = 3;
+        let grid_depth = 3; 
+        let grid_height = 3;
+        let grid = Grid::<u32>::new(grid_width, grid_depth, grid_height);
+        assert_eq!(grid.cell_count(), 27);
+    }
+
+    #[test]
+    fn test_grid_cell_count_matches_data() {
+        let grid = Grid::<Voxel>::new(70_000, 70_000, 0);
+        assert_eq!(grid.cell_count(), 0);
+        let grid = Grid::<Voxel>::new(5, 4, 3);
+        assert_eq!(grid.cell_count(), grid.data.len() / Voxel::SIZE as usize);
+        assert_eq!(grid.coordinate(59), Some((4, 3, 2)));
+        assert_eq!(grid.coordinate(60), None);
+    }
+
+    #[test]
+    fn test_vox_write() {
+        let grid_width = 3;
+        let grid_depth = 3; 
+        let grid_height = 3;
+        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+        let black = [0, 0, 0, 255];
+        let white = [255, 255, 255, 255];
+        let voxel_black = Voxel::from_rgba(&black);
+        let voxel_white = Voxel::from_rgba(&white);
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    if z == 0 {
+                        *grid.get_mut(x, y, z) = voxel_black;
+                    } else {
+                        *grid.get_mut(x, y, z) = voxel_white;
+                    }
+                }
+            }
+        }
+        let bytes = vox::encode(&grid).unwrap();
+        fs::write("test.vox", &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_vox_write_transparent_voxel() {
+        let grid_width = 3;
+        let grid_depth = 3;
+        let grid_height = 3;
+        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+
+        let blue = [0, 0, 255, 0];
+        let voxel = Voxel::from_rgba(&blue);
+        for x in 0..grid_width {
+            for y in 0..grid_depth {
+                for z in 0..grid_height {
+                    *grid.get_mut(x, y, z) = voxel;
+                }
+            }
+        }
+        let bytes = vox::encode(&grid).unwrap();
+        fs::write("test_transparent.vox", &bytes).unwrap();
+    }
+
+    fn gen_test_road_edge() -> Grid<Voxel> {
+        let width = 3;
+        let depth = 3;
+        let height = 3;
+        let mut grid = Grid::new(width, depth, height);
+        let grey = Voxel::from_rgba(&[108, 108, 127, 255]);
+        let green = Voxel::from_rgba(&[90, 120, 20, 255]);
+        let brown = Voxel::from_rgba(&[120, 80, 50, 255]);
+        for x in 0..width {
+            for y in 0..depth {
+                for z in 0..height {
+                    if z == height - 1 && x <= width / 2 {
+                        *grid.get_mut(x, y, z) = grey;
+                    } else if z == height - 1 {
+                        *grid.get_mut(x, y, z) = green;
+                    } else {
+                        *grid.get_mut(x, y, z) = brown;
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn test_voxel_rotated_z_0() {
+        let grid = gen_test_road_edge();
+        let rotated = grid.rotated_z(&Rotation::R0);
+        let bytes = vox::encode(&rotated).unwrap();
+        fs::write("test_road_rotated_z_0.vox", &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_voxel_rotated_z_90() {
+        let grid = gen_test_road_edge();
+        let rotated = grid.rotated_z(&Rotation::R90);
+        let bytes = vox::encode(&rotated).unwrap();
+        fs::write("test_road_rotated_z_90.vox", &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_voxel_rotated_z_180() {
+        let grid = gen_test_road_edge();
+        let rotated = grid.rotated_z(&Rotation::R180);
+        let bytes = vox::encode(&rotated).unwrap();
+        fs::write("test_road_rotated_z_180.vox", &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_voxel_rotated_z_270() {
+        let grid = gen_test_road_edge();
+        let rotated = grid.rotated_z(&Rotation::R270);
+        let bytes = vox::encode(&rotated).unwrap();
+        fs::write("test_road_rotated_z_270.vox", &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_grid_diff() {
+        let mut a = Grid::<u32>::new(3, 3, 3);
+        let mut b = Grid::<u32>::new(3, 3, 3);
+        *a.get_mut(1, 1, 1) = 5;
+        *b.get_mut(1, 1, 1) = 5;
+        *b.get_mut(0, 0, 0) = 1;
+        *b.get_mut(2, 1, 0) = 2;
+        assert_eq!(a.diff(&b), vec![(0, 0, 0, 1), (2, 1, 0, 2)]);
+        assert!(a.diff(&a).is_empty());
+    }
+
+    #[test]
+    fn test_grid_apply_patch() {
+        let mut grid = Grid::<u32>::new(3, 3, 3);
+        *grid.get_mut(1, 1, 1) = 7;
+        let original = grid.clone();
+        let patch = [(1, 1, 1, 1), (0, 2, 1, 2), (1, 1, 1, 3)];
+        let inverse = grid.apply_patch(&patch);
+        assert_eq!(*grid.get(1, 1, 1), 3);
+        assert_eq!(*grid.get(0, 2, 1), 2);
+        grid.apply_patch(&inverse);
+        assert_eq!(grid, original);
+    }
+
+    #[test]
+    fn test_grid_layers_mut() {
+        let mut grid = Grid::<u32>::new(2, 2, 3);
+        grid.map_in_place(|t| *t = 5);
+        for (z, layer) in grid.layers_mut() {
+            assert_eq!(layer.len(), 16);
+            if z == 1 {
+                layer.fill(0);
+            }
+        }
+        for (_, _, z, t) in grid.enumerate_cells() {
+            assert_eq!(*t, if z == 1 { 0 } else { 5 });
+        }
+    }
+
+    #[test]
+    fn test_grid_for_each_mut() {
+        let mut grid = Grid::<u32>::new(2, 2, 3);
+        grid.for_each_mut(|_, _, z, t| *t += z);
+        for (_, _, z, t) in grid.enumerate_cells() {
+            assert_eq!(*t, z);
+        }
+    }
+
+    // Splits VOX bytes into the (id, content) pairs of MAIN's children
+    fn vox_chunks(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut i = 20;
+        while i < bytes.len() {
+            let id = &bytes[i..i + 4];
+            let size = u32::from_le_bytes(bytes[i + 4..i + 8].try_into().unwrap()) as usize;
+            chunks.push((id, &bytes[i + 12..i + 12 + size]));
+            i += 12 + size;
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_vox_encode_models_layered() {
+        let ground = gen_test_road_edge();
+        let mut prop = Grid::new(1, 1, 2);
+        *prop.get_mut(0, 0, 0) = Voxel::from_rgba(&RED);
+        *prop.get_mut(0, 0, 1) = Voxel::from_rgba(&RED);
+        let bytes = vox::encode_models_layered(&[
+            ("ground", vec![(&ground, [0, 0, 0])]),
+            ("props", vec![(&prop, [1, 1, 3]), (&prop, [2, 0, 3])]),
+        ])
+        .unwrap();
+        let chunks = vox_chunks(&bytes);
+        assert_eq!(chunks.iter().filter(|(id, _)| *id == b"XYZI").count(), 3);
+        let layers: Vec<&[u8]> = chunks
+            .iter()
+            .filter(|(id, _)| *id == b"LAYR")
+            .map(|(_, content)| *content)
+            .collect();
+        assert_eq!(layers.len(), 2);
+        for (name, layer) in ["ground", "props"].iter().zip(layers) {
+            // layer id, pair count, key "_name", then the value string
+            assert_eq!(&layer[17..21], (name.len() as u32).to_le_bytes());
+            assert_eq!(&layer[21..21 + name.len()], name.as_bytes());
+        }
+        fs::write("test_layered.vox", &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_grid_trim_with_offset() {
+        let mut grid = Grid::new(5, 5, 5);
+        *grid.get_mut(2, 2, 2) = RED_VOXEL;
+        *grid.get_mut(3, 2, 4) = RED_VOXEL;
+        let (trimmed, offset) = grid.trim_with_offset().unwrap();
+        assert_eq!(offset, (2, 2, 2));
+        assert_eq!((trimmed.width(), trimmed.depth(), trimmed.height()), (2, 1, 3));
+        assert_eq!(*trimmed.get(0, 0, 0), RED_VOXEL);
+        assert_eq!(*trimmed.get(1, 0, 2), RED_VOXEL);
+        assert_eq!(Some(trimmed), grid.trim());
+        assert!(Grid::<Voxel>::new(2, 2, 2).trim_with_offset().is_none());
+    }
+
+    #[test]
+    fn test_grid_macro() {
+        let green = [90, 120, 20, 255];
+        let grid = grid![&[('r', RED), ('g', green)];
+            [['r', 'g'],
+             ['g', 'g']],
+        ];
+        let mut expected = Grid::new(2, 2, 1);
+        *expected.get_mut(0, 0, 0) = RED_VOXEL;
+        *expected.get_mut(1, 0, 0) = Voxel::from_rgba(&green);
+        *expected.get_mut(0, 1, 0) = Voxel::from_rgba(&green);
+        *expected.get_mut(1, 1, 0) = Voxel::from_rgba(&green);
+        assert_eq!(grid, expected);
+    }
+
+    #[test]
+    fn test_grid_macro_layers() {
+        let grid = grid![&[('r', RED), ('.', [0; 4])];
+            [['r', '.']],
+            [['.', 'r']],
+        ];
+        assert_eq!((grid.width(), grid.depth(), grid.height()), (2, 1, 2));
+        assert_eq!(*grid.get(0, 0, 0), RED_VOXEL);
+        assert_eq!(grid.get(1, 0, 0).as_rgba(), [0; 4]);
+        assert_eq!(*grid.get(1, 0, 1), RED_VOXEL);
+    }
+
+    #[test]
+    fn test_grid_sample_trilinear() {
+        let mut grid = Grid::<u8>::new(3, 3, 3);
+        *grid.get_mut(1, 1, 1) = 100;
+        *grid.get_mut(2, 1, 1) = 200;
+        assert_eq!(grid.sample_trilinear(1.0, 1.0, 1.0), 100.0);
+        assert_eq!(grid.sample_trilinear(2.0, 1.0, 1.0), 200.0);
+        assert_eq!(grid.sample_trilinear(1.5, 1.0, 1.0), 150.0);
+        assert_eq!(grid.sample_trilinear(5.0, 1.0, 1.0), 200.0);
+        assert_eq!(grid.sample_trilinear(-1.0, -1.0, -1.0), 0.0);
+        assert_eq!(Grid::<u8>::new(3, 0, 3).sample_trilinear(1.0, 1.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_grid_map_in_place() {
+        let mut grid = Grid::<u32>::new(2, 2, 1);
+        for (id, (_, _, _, t)) in grid.enumerate_cells_mut().enumerate() {
+            *t = id as u32;
+        }
+        grid.map_in_place(|t| *t *= 2);
+        assert_eq!(grid.data, [0, 2, 4, 6].map(u32::to_ne_bytes).concat());
+    }
+
+    #[test]
+    fn test_grid_map_region() {
+        let mut grid = Grid::<u32>::new(4, 4, 4);
+        grid.map_region((1, 1, 1), (2, 2, 2), |x, _, _, t| *t = x + 1);
+        grid.map_region((3, 3, 3), (9, 9, 9), |_, _, _, t| *t = 9);
+        for (x, y, z, t) in grid.enumerate_cells() {
+            let inside = [x, y, z].iter().all(|c| (1..=2).contains(c));
+            if inside {
+                assert_eq!(*t, x + 1);
+            } else if (x, y, z) == (3, 3, 3) {
+                assert_eq!(*t, 9);
+            } else {
+                assert_eq!(*t, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_grid_draw_sphere() {
+        let mut grid = Grid::<u8>::new(7, 7, 7);
+        grid.draw_sphere([3.0, 3.0, 3.0], 2.0, 1);
+        for (x, y, z, t) in grid.enumerate_cells() {
+            let d2 = [x, y, z].iter().map(|c| (*c as i32 - 3).pow(2)).sum::<i32>();
+            assert_eq!(*t == 1, d2 <= 4);
+        }
+    }
+
+    #[test]
+    fn test_grid_draw_sphere_shell() {
+        let mut grid = Grid::<u8>::new(7, 7, 7);
+        grid.draw_sphere([3.0, 3.0, 3.0], 1.0, 5);
+        grid.draw_sphere_shell([3.0, 3.0, 3.0], 2.0, 1.0, 1);
+        for (x, y, z, t) in grid.enumerate_cells() {
+            let d2 = [x, y, z].iter().map(|c| (*c as i32 - 3).pow(2)).sum::<i32>();
+            if d2 <= 1 {
+                assert_eq!(*t, 5);
+            } else if d2 <= 4 {
+                assert_eq!(*t, 1);
+            } else {
+                assert_eq!(*t, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_grid_shift_in_place() {
+        let mut grid = Grid::<u32>::new(3, 3, 2);
+        for (x, y, z, t) in grid.enumerate_cells_mut() {
+            *t = 1 + x + 3 * y + 9 * z;
+        }
+        let original = grid.clone();
+        grid.shift_in_place(1, 1, 0, 0);
+        for (x, y, z, t) in grid.enumerate_cells() {
+            if x == 0 || y == 0 {
+                assert_eq!(*t, 0);
+            } else {
+                assert_eq!(*t, *original.get(x - 1, y - 1, z));
+            }
+        }
+        grid.shift_in_place(-1, -1, 0, 0);
+        for (x, y, z, t) in grid.enumerate_cells() {
+            if x == 2 || y == 2 {
+                assert_eq!(*t, 0);
+            } else {
+                assert_eq!(*t, *original.get(x, y, z));
+            }
+        }
+    }
+
+    #[test]
+    fn test_orientation_all() {
+        let orientations = Orientation::all();
+        assert_eq!(orientations.len(), 24);
+        assert_eq!(orientations[0], Orientation::IDENTITY);
+        for (i, a) in orientations.iter().enumerate() {
+            assert!(!orientations[i + 1..].contains(a));
+        }
+    }
+
+    #[test]
+    fn test_grid_orient() {
+        let mut grid = Grid::<u32>::new(2, 3, 4);
+        *grid.get_mut(1, 0, 0) = 1;
+        let rotated = grid.orient(Orientation::from(&Rotation::R90));
+        assert_eq!((rotated.width(), rotated.depth(), rotated.height()), (3, 2, 4));
+        assert_eq!(*rotated.get(2, 1, 0), 1);
+        assert_eq!(grid.orient(Orientation::IDENTITY), grid);
+    }
+
+    #[test]
+    fn test_grid_is_rotation_of() {
+        let grid = gen_test_road_edge();
+        let rotated = grid.rotated_z(&Rotation::R90);
+        assert_eq!(rotated.is_rotation_of(&grid), Some(Orientation::from(&Rotation::R90)));
+        assert_eq!(grid.is_rotation_of(&grid), Some(Orientation::IDENTITY));
+        let mut other = gen_test_road_edge();
+        *other.get_mut(0, 0, 0) = RED_VOXEL;
+        assert_eq!(other.is_rotation_of(&grid), None);
+    }
+
+    #[test]
+    fn test_grid_center_of_mass() {
+        let mut grid = Grid::new(4, 4, 4);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            if x > 0 && y > 0 && z > 0 {
+                *v = RED_VOXEL;
+            }
+        }
+        assert_eq!(grid.center_of_mass(), [2.0, 2.0, 2.0]);
+        assert!(Grid::<Voxel>::new(2, 2, 2).center_of_mass()[0].is_nan());
+    }
+
+    #[test]
+    fn test_grid_exterior_mask() {
+        let mut grid = Grid::new(5, 5, 5);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            let shell = [x, y, z].iter().any(|c| *c == 1 || *c == 3);
+            let inside = [x, y, z].iter().all(|c| (1..=3).contains(c));
+            if shell && inside {
+                *v = RED_VOXEL;
+            }
+        }
+        let mask = grid.exterior_mask();
+        assert_eq!(*mask.get(2, 2, 2), 0);
+        assert_eq!(*mask.get(1, 1, 1), 0);
+        assert_eq!(*mask.get(0, 0, 0), 1);
+        assert_eq!(*mask.get(0, 2, 2), 1);
+        assert_eq!(*mask.get(4, 4, 4), 1);
+        assert_eq!(mask.enumerate_cells().filter(|(_, _, _, m)| **m == 1).count(), 125 - 27);
+    }
+
+    #[test]
+    fn test_vox_validate() {
+        let bytes = vox::encode(&gen_test_road_edge()).unwrap();
+        assert_eq!(vox::validate(&bytes), Ok(()));
+        let layered = vox::encode_models_layered(&[("a", vec![(&gen_test_road_edge(), [0; 3])])]);
+        assert_eq!(vox::validate(&layered.unwrap()), Ok(()));
+        let error = vox::validate(&bytes[..bytes.len() - 10]).unwrap_err();
+        assert!(matches!(error, vox::VoxError::Truncated { .. }));
+        assert!(error.to_string().starts_with("VOX truncated"));
+        assert_eq!(vox::validate(b"VOX"), Err(vox::VoxError::BadMagic));
+    }
+
+    #[test]
+    fn test_grid_remap_palette() {
+        let mut grid = Grid::<u8>::new(2, 1, 1);
+        *grid.get_mut(0, 0, 0) = 1;
+        *grid.get_mut(1, 0, 0) = 2;
+        let original = grid.clone();
+        let mut mapping = [0; 256];
+        for (i, m) in mapping.iter_mut().enumerate() {
+            *m = i as u8;
+        }
+        grid.remap_palette(&mapping);
+        assert_eq!(grid, original);
+        mapping.swap(1, 2);
+        grid.remap_palette(&mapping);
+        assert_eq!((*grid.get(0, 0, 0), *grid.get(1, 0, 0)), (2, 1));
+    }
+
+    #[test]
+    fn test_grid_remap_palette_rgba() {
+        let mut grid = Grid::<u8>::new(2, 1, 1);
+        *grid.get_mut(0, 0, 0) = 1;
+        *grid.get_mut(1, 0, 0) = 2;
+        let mut palette = [[0; 4]; 256];
+        for (i, color) in palette.iter_mut().enumerate() {
+            *color = [i as u8, 0, 0, 255];
+        }
+        let colored = grid.remap_palette_rgba(&palette);
+        for (x, y, z, v) in colored.enumerate_cells() {
+            assert_eq!(v.as_rgba(), [*grid.get(x, y, z), 0, 0, 255]);
+        }
+        palette.swap(1, 2);
+        let swapped = grid.remap_palette_rgba(&palette);
+        assert_eq!(swapped.get(0, 0, 0).as_rgba(), [2, 0, 0, 255]);
+        assert_eq!(swapped.get(1, 0, 0).as_rgba(), [1, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_vox_decode() {
+        let grid = gen_test_road_edge();
+        let bytes = vox::encode(&grid).unwrap();
+        assert_eq!(vox::decode(&bytes).unwrap(), grid);
+        let error = vox::decode(&bytes[..100]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_vox_decode_versions() {
+        let ground = gen_test_road_edge();
+        let bytes = vox::encode(&ground).unwrap();
+        assert_eq!(&bytes[4..8], 150u32.to_le_bytes());
+        assert_eq!(vox::decode(&bytes).unwrap(), ground);
+        let mut prop = Grid::new(1, 1, 2);
+        *prop.get_mut(0, 0, 1) = RED_VOXEL;
+        let mut bytes = vox::encode_models_layered(&[
+            ("ground", vec![(&ground, [0, 0, 0])]),
+            ("props", vec![(&prop, [1, 2, 3])]),
+        ])
+        .unwrap();
+
+        // Version 200 assembles the scene graph
+        assert_eq!(&bytes[4..8], 200u32.to_le_bytes());
+        let scene = vox::decode(&bytes).unwrap();
+        assert_eq!((scene.width(), scene.depth(), scene.height()), (3, 3, 5));
+        for (x, y, z, v) in ground.enumerate_cells() {
+            assert_eq!(scene.get(x, y, z), v);
+        }
+        assert_eq!(*scene.get(1, 2, 4), RED_VOXEL);
+        assert_eq!(scene.get(1, 2, 3).as_rgba()[3], 0);
+
+        // Version 150 reads the first model as laid out, even with a scene graph
+        bytes[4..8].copy_from_slice(&150u32.to_le_bytes());
+        assert_eq!(vox::decode(&bytes).unwrap(), ground);
+    }
+
+    #[test]
+    fn test_vox_decode_encoded_models() {
+        let mut speck = Grid::new(1, 1, 1);
+        *speck.get_mut(0, 0, 0) = RED_VOXEL;
+        let model = |offset| vox::Model { grid: speck.clone(), offset, name: None, shape_name: None };
+        let bytes = vox::encode_models(&[model([0, 0, 0]), model([3, 1, 2])]).unwrap();
+        let scene = vox::decode(&bytes).unwrap();
+        assert_eq!((scene.width(), scene.depth(), scene.height()), (4, 2, 3));
+        let opaque = scene.positions_of(|v| v.as_rgba()[3] > 0);
+        assert_eq!(opaque, vec![(0, 0, 0), (3, 1, 2)]);
+    }
+
+    #[test]
+    fn test_vox_encode_streams_xyzi() {
+        let mut grid = gen_test_road_edge().with_border(1, Voxel::from_rgba(&[0; 4]));
+        grid.add_colored_sphere([2.0, 2.0, 2.0], 2.0, RED);
+        *grid.get_mut(4, 4, 4) = Voxel::from_rgba(&[9, 9, 9, 0]);
+        let bytes = vox::encode(&grid).unwrap();
+
+        // Collect the voxels up front as the encoder used to
+        let mut colors: Vec<[u8; 4]> = Vec::new();
+        let mut xyzis = Vec::new();
+        for (x, y, z, v) in grid.enumerate_cells() {
+            let rgba: [u8; 4] = (*v).into();
+            let index = colors.iter().position(|c| *c == rgba).unwrap_or_else(|| {
+                colors.push(rgba);
+                colors.len() - 1
+            });
+            if rgba[3] > 0 {
+                xyzis.extend([x as u8, y as u8, z as u8, index as u8 + 1]);
+            }
+        }
+        colors.resize(256, [0; 4]);
+        let mut expected = b"VOX ".to_vec();
+        expected.extend(150u32.to_le_bytes());
+        expected.extend(b"MAIN");
+        expected.extend(0u32.to_le_bytes());
+        expected.extend((12 * 3 + 12 + 4 + xyzis.len() as u32 + 1024).to_le_bytes());
+        expected.extend(b"SIZE");
+        expected.extend([12, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0]);
+        expected.extend(b"XYZI");
+        expected.extend((4 + xyzis.len() as u32).to_le_bytes());
+        expected.extend(0u32.to_le_bytes());
+        expected.extend((xyzis.len() as u32 / 4).to_le_bytes());
+        expected.extend(&xyzis);
+        expected.extend(b"RGBA");
+        expected.extend(1024u32.to_le_bytes());
+        expected.extend(0u32.to_le_bytes());
+        expected.extend(colors.concat());
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_vox_encode_fixed_bytes() {
+        // Every multi-byte field is little endian and voxels are written byte
+        // by byte, so this holds on any host
+        let mut grid = Grid::new(2, 1, 1);
+        *grid.get_mut(0, 0, 0) = Voxel::from_rgba(&[1, 2, 3, 255]);
+        let bytes = vox::encode(&grid).unwrap();
+        #[rustfmt::skip]
+        let header = [
+            b'V', b'O', b'X', b' ', 0x96, 0x00, 0x00, 0x00,
+            b'M', b'A', b'I', b'N', 0x00, 0x00, 0x00, 0x00, 0x38, 0x04, 0x00, 0x00,
+            b'S', b'I', b'Z', b'E', 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            b'X', b'Y', b'Z', b'I', 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            b'R', b'G', b'B', b'A', 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x02, 0x03, 0xff,
+        ];
+        assert_eq!(bytes.len(), header.len() + 255 * 4);
+        assert_eq!(bytes[..header.len()], header);
+        assert!(bytes[header.len()..].iter().all(|b| *b == 0));
+        assert!(vox::decode(&bytes).unwrap().visually_eq(&grid));
+    }
+
+    #[test]
+    fn test_vox_decode_indexed() {
+        let mut grid = gen_test_road_edge();
+        *grid.get_mut(1, 1, 1) = Voxel::from_rgba(&[0; 4]);
+        let bytes = vox::encode(&grid).unwrap();
+        let (indices, palette) = vox::decode_indexed(&bytes).unwrap();
+        assert_eq!(palette.len(), 256);
+        assert_eq!(*indices.get(1, 1, 1), 0);
+        assert_eq!(indices.distinct_values(), 4);
+        let decoded = vox::decode(&bytes).unwrap();
+        for ((.., i), (.., v)) in indices.enumerate_cells().zip(decoded.enumerate_cells()) {
+            assert_eq!(palette[*i as usize], v.as_rgba());
+        }
+    }
+
+    #[test]
+    fn test_vox_chunk_sizes_overflow() {
+        assert_eq!(vox::chunk_sizes(2), Ok((2, 12, 36 + 12 + 1024 + 12)));
+        let largest = (u32::MAX as usize - 36 - 12 - 1024 - 4) / 4;
+        assert!(vox::chunk_sizes(largest).is_ok());
+        assert_eq!(vox::chunk_sizes(largest + 1), Err(vox::VoxError::SizeOverflow));
+        assert_eq!(vox::chunk_sizes(u32::MAX as usize / 4 + 1), Err(vox::VoxError::SizeOverflow));
+        let error = std::io::Error::from(vox::chunk_sizes(usize::MAX).unwrap_err());
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let source = error.get_ref().and_then(|e| e.downcast_ref::<vox::VoxError>());
+        assert_eq!(source, Some(&vox::VoxError::SizeOverflow));
+        // The scene, metadata and dict encoders write their sizes through this
+        assert_eq!(vox::size_field(u32::MAX as usize), Ok(u32::MAX.to_le_bytes()));
+        assert_eq!(vox::size_field(u32::MAX as usize + 1), Err(vox::VoxError::SizeOverflow));
+    }
+
+    #[test]
+    fn test_vox_decode_reader() {
+        let mut metadata = std::collections::HashMap::new();
+        metadata.insert("source".to_string(), "test".to_string());
+        let bytes = vox::encode_with_metadata(&gen_test_road_edge(), &metadata).unwrap();
+        let mut cursor = std::io::Cursor::new(&bytes);
+        assert_eq!(vox::decode_reader(&mut cursor).unwrap(), vox::decode(&bytes).unwrap());
+        let mut truncated = std::io::Cursor::new(&bytes[..bytes.len() - 1]);
+        assert!(vox::decode_reader(&mut truncated).is_err());
+
+        // A scene stacks every model, as decode does
+        let mut prop = Grid::new(1, 1, 2);
+        *prop.get_mut(0, 0, 1) = RED_VOXEL;
+        let ground = gen_test_road_edge();
+        let bytes = vox::encode_models(&[
+            vox::Model { grid: ground, offset: [0, 0, 0], name: None, shape_name: None },
+            vox::Model { grid: prop, offset: [1, 2, 3], name: None, shape_name: None },
+        ])
+        .unwrap();
+        let mut cursor = std::io::Cursor::new(&bytes);
+        let scene = vox::decode_reader(&mut cursor).unwrap();
+        assert_eq!(scene, vox::decode(&bytes).unwrap());
+        assert_eq!(*scene.get(1, 2, 4), RED_VOXEL);
+    }
+
+    #[test]
+    fn test_vox_load_and_stack() {
+        let ground = gen_test_road_edge();
+        let mut prop = Grid::new(1, 1, 2);
+        *prop.get_mut(0, 0, 0) = RED_VOXEL;
+        *prop.get_mut(0, 0, 1) = RED_VOXEL;
+        fs::write("test_stack_ground.vox", vox::encode(&ground).unwrap()).unwrap();
+        fs::write("test_stack_prop.vox", vox::encode(&prop).unwrap()).unwrap();
+        let stacked = vox::load_and_stack(&[
+            (std::path::Path::new("test_stack_ground.vox"), [0, 0, 0]),
+            (std::path::Path::new("test_stack_prop.vox"), [-1, 1, 2]),
+        ])
+        .unwrap();
+        assert_eq!((stacked.width(), stacked.depth(), stacked.height()), (4, 3, 4));
+        for (x, y, z, v) in ground.enumerate_cells() {
+            if (x, y, z) != (0, 1, 2) {
+                assert_eq!(stacked.get(x + 1, y, z), v);
+            }
+        }
+        assert_eq!(*stacked.get(0, 1, 2), RED_VOXEL);
+        assert_eq!(*stacked.get(0, 1, 3), RED_VOXEL);
+        assert_eq!(stacked.get(0, 0, 0).as_rgba()[3], 0);
+    }
+
+    #[test]
+    fn test_vox_metadata_round_trip() {
+        let grid = gen_test_road_edge();
+        let mut metadata = std::collections::HashMap::new();
+        metadata.insert("author".to_string(), "sol".to_string());
+        let bytes = vox::encode_with_metadata(&grid, &metadata).unwrap();
+        assert_eq!(vox::validate(&bytes), Ok(()));
+        let (decoded, decoded_metadata) = vox::decode_with_metadata(&bytes).unwrap();
+        assert_eq!(decoded, grid);
+        assert_eq!(decoded_metadata, metadata);
+    }
+
+    #[test]
+    fn test_vox_render_settings() {
+        let grid = gen_test_road_edge();
+        let settings = vox::RenderSettings { ground_color: [1, 2, 3], ..Default::default() };
+        let bytes = vox::encode_with_render_settings(&grid, &settings).unwrap();
+        assert_eq!(vox::validate(&bytes), Ok(()));
+        assert_eq!(vox::decode(&bytes).unwrap(), grid);
+        let mut ground = b"rOBJ".to_vec();
+        ground.extend(43u32.to_le_bytes());
+        ground.extend(0u32.to_le_bytes());
+        ground.extend(2u32.to_le_bytes());
+        for s in ["_type", "_ground", "_color", "1 2 3"] {
+            ground.extend((s.len() as u32).to_le_bytes());
+            ground.extend(s.as_bytes());
+        }
+        let plain = vox::encode(&grid).unwrap();
+        assert_eq!(bytes[plain.len()..plain.len() + ground.len()], ground);
+        let tail = String::from_utf8_lossy(&bytes[plain.len() + ground.len()..]);
+        assert!(tail.contains("_sky") && tail.contains("216 216 216"));
+        assert!(tail.contains("_bloom") && tail.contains("_threshold"));
+    }
+
+    #[test]
+    fn test_vox_index_grid_round_trip() {
+        let grid = gen_test_road_edge();
+        let (indices, palette) = vox::to_index_grid(&grid);
+        assert_eq!(palette.len(), 3);
+        assert_eq!(*indices.get(0, 0, 0), 0);
+        assert_eq!(palette[*indices.get(0, 0, 2) as usize], [108, 108, 127, 255]);
+        assert_eq!(vox::from_index_grid(&indices, &palette), grid);
+    }
+
+    #[test]
+    fn test_grid_contains() {
+        let grid = Grid::<u8>::new(2, 3, 4);
+        assert!(grid.contains(0, 0, 0));
+        assert!(grid.contains(1, 2, 3));
+        assert!(!grid.contains(2, 2, 3));
+        assert!(!grid.contains(1, 3, 3));
+        assert!(!grid.contains(1, 2, 4));
+    }
+
+    #[test]
+    fn test_grid_reinterpret() {
+        let mut grid = Grid::<[u8; 4]>::new(2, 1, 1);
+        *grid.get_mut(1, 0, 0) = 7u32.to_ne_bytes();
+        let words = grid.reinterpret::<u32>().unwrap();
+        assert_eq!((words.width(), words.depth(), words.height()), (2, 1, 1));
+        assert_eq!(*words.get(1, 0, 0), 7);
+        let voxels = words.reinterpret::<Voxel>().unwrap();
+        assert_eq!(voxels.width(), 1);
+        let odd = voxels.reinterpret::<u32>().unwrap().reinterpret::<u8>().unwrap();
+        assert_eq!(odd.width(), 8);
+        assert!(Grid::<u8>::new(3, 1, 1).reinterpret::<u32>().is_err());
+        assert!(Grid::<Voxel>::new(u32::MAX, 1, 0).reinterpret::<u8>().is_err());
+    }
+
+    #[test]
+    fn test_grid_color_mask() {
+        let grid = gen_test_road_edge();
+        let grey = [108, 108, 127, 255];
+        let mask = grid.color_mask(grey);
+        for (x, y, z, m) in mask.enumerate_cells() {
+            assert_eq!(*m == 1, grid.get(x, y, z).as_rgba() == grey);
+        }
+        assert_eq!(mask.enumerate_cells().filter(|(_, _, _, m)| **m == 1).count(), 6);
+    }
+
+    #[test]
+    fn test_grid_label_components() {
+        let mut grid = Grid::new(4, 4, 1);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (2, 2), (3, 3)] {
+            *grid.get_mut(x, y, 0) = RED_VOXEL;
+        }
+        let (labels, count) = grid.label_components(Connectivity::Face);
+        assert_eq!(count, 3);
+        assert_eq!(*labels.get(0, 0, 0), 1);
+        assert_eq!(*labels.get(0, 1, 0), 1);
+        assert_eq!(*labels.get(2, 2, 0), 2);
+        assert_eq!(*labels.get(3, 3, 0), 3);
+        assert_eq!(*labels.get(3, 0, 0), 0);
+        let (labels, count) = grid.label_components(Connectivity::Edge);
+        assert_eq!(count, 2);
+        assert_eq!(*labels.get(3, 3, 0), 2);
+    }
+
+    #[test]
+    fn test_grid_keep_largest_component() {
+        let mut grid = Grid::new(5, 5, 5);
+        *grid.get_mut(0, 0, 0) = RED_VOXEL;
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            if x >= 2 && y >= 2 && z >= 2 {
+                *v = RED_VOXEL;
+            }
+        }
+        let kept = grid.keep_largest_component();
+        assert_eq!(kept.get(0, 0, 0).as_rgba()[3], 0);
+        assert_eq!(*kept.get(3, 3, 3), RED_VOXEL);
+        assert_eq!(kept.enumerate_cells().filter(|(_, _, _, v)| v.as_rgba()[3] > 0).count(), 27);
+    }
+
+    #[test]
+    fn test_grid_raycast_all() {
+        let mut grid = Grid::new(5, 3, 3);
+        for x in 1..4 {
+            *grid.get_mut(x, 1, 1) = RED_VOXEL;
+        }
+        let hits = grid.raycast_all([-1.0, 1.5, 1.5], [1.0, 0.0, 0.0], 10.0);
+        assert_eq!(hits, vec![(1, 1, 1), (2, 1, 1), (3, 1, 1)]);
+        let hits = grid.raycast_all([6.0, 1.5, 1.5], [-2.0, 0.0, 0.0], 3.5);
+        assert_eq!(hits, vec![(3, 1, 1), (2, 1, 1)]);
+        assert!(grid.raycast_all([-1.0, 0.5, 1.5], [1.0, 0.0, 0.0], 10.0).is_empty());
+    }
+
+    #[test]
+    fn test_grid_terrain() {
+        let grass = Voxel::from_rgba(&[90, 120, 20, 255]);
+        let dirt = Voxel::from_rgba(&[120, 80, 50, 255]);
+        let a = Grid::terrain(16, 16, 8, 1, grass, dirt);
+        assert_eq!(a, Grid::terrain(16, 16, 8, 1, grass, dirt));
+        assert_ne!(a, Grid::terrain(16, 16, 8, 2, grass, dirt));
+        for x in 0..16 {
+            for y in 0..16 {
+                let column: Vec<_> = (0..8).map(|z| *a.get(x, y, z)).collect();
+                let surface = column.iter().position(|v| *v == grass).unwrap();
+                assert!(column[..surface].iter().all(|v| *v == dirt));
+                assert!(column[surface + 1..].iter().all(|v| v.as_rgba()[3] == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_vox_encode_report() {
+        let mut grid = Grid::new(2, 2, 1);
+        *grid.get_mut(0, 0, 0) = RED_VOXEL;
+        *grid.get_mut(1, 0, 0) = RED_VOXEL;
+        *grid.get_mut(0, 1, 0) = Voxel::from_rgba(&[0, 0, 255, 255]);
+        let report = vox::encode_report(&grid).unwrap();
+        assert_eq!(report.voxel_count, 3);
+        assert_eq!(report.distinct_colors, 3);
+        assert_eq!(report.index_counts[1], 2);
+        assert_eq!(report.index_counts[2], 1);
+        assert_eq!(report.index_counts[3], 0);
+        assert_eq!(report.skipped_transparent, 1);
+    }
+
+    #[test]
+    fn test_vox_palette() {
+        let mut palette = vox::Palette::new();
+        assert_eq!(palette.insert(RED), Some(0));
+        assert_eq!(palette.insert([0, 255, 0, 255]), Some(1));
+        assert_eq!(palette.insert(RED), Some(0));
+        assert_eq!(palette.index_of([0, 255, 0, 255]), Some(1));
+        assert_eq!(palette.index_of([0, 0, 255, 255]), None);
+        assert_eq!(palette.color(1), [0, 255, 0, 255]);
+        for i in 2..256 {
+            assert_eq!(palette.insert([i as u8, 0, 0, 0]), Some(i as u8));
+        }
+        assert_eq!(palette.len(), 256);
+        assert_eq!(palette.insert([1, 2, 3, 4]), None);
+    }
+
+    #[test]
+    fn test_vox_palette_nearest() {
+        let mut palette = vox::Palette::new();
+        palette.insert([0, 0, 0, 255]);
+        palette.insert(RED);
+        palette.insert([0, 0, 255, 255]);
+        assert_eq!(palette.nearest([240, 12, 8, 255]), 1);
+        assert_eq!(palette.nearest([10, 10, 200, 255]), 2);
+        assert_eq!(vox::Palette::new().nearest(RED), 0);
+    }
+
+    #[test]
+    fn test_vox_encode_palette_full() {
+        let mut grid = Grid::new(16, 16, 1);
+        for (x, y, _, v) in grid.enumerate_cells_mut() {
+            *v = Voxel::from_rgba(&[x as u8, y as u8, 0, 255]);
+        }
+        assert!(vox::encode(&grid).is_err());
+        *grid.get_mut(15, 15, 0) = *grid.get(0, 0, 0);
+        assert!(vox::encode(&grid).is_ok());
+    }
+
+    #[test]
+    fn test_grid_with_border() {
+        let mut grid = Grid::<u32>::new(1, 1, 1);
+        *grid.get_mut(0, 0, 0) = 1;
+        let bordered = grid.with_border(1, 9);
+        assert_eq!((bordered.width(), bordered.depth(), bordered.height()), (3, 3, 3));
+        for (x, y, z, t) in bordered.enumerate_cells() {
+            let expected = if (x, y, z) == (1, 1, 1) { 1 } else { 9 };
+            assert_eq!(*t, expected);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Grid bordered dimension overflows u32")]
+    fn test_grid_with_border_overflow() {
+        Grid::<u8>::new(1, 1, 1).with_border(u32::MAX / 2 + 1, 0);
+    }
+
+    #[test]
+    fn test_mesh_from_grid() {
+        let mut grid = Grid::new(3, 1, 1);
+        *grid.get_mut(0, 0, 0) = RED_VOXEL;
+        *grid.get_mut(1, 0, 0) = RED_VOXEL;
+        let mesh = mesh::Mesh::from_grid(&grid);
+        // Two touching voxels expose 10 faces
+        assert_eq!(mesh.positions.len(), 40);
+        assert_eq!(mesh.indices.len(), 60);
+        assert!(mesh.colors.iter().all(|c| *c == RED));
+        assert!(mesh.positions.iter().all(|p| p[0] <= 2.0));
+    }
+
+    // Just enough JSON to check the glTF output, which has no escapes
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Bool(bool),
+        Number(f64),
+        String(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn parse(text: &str) -> Json {
+            let mut chars = text.chars().peekable();
+            let json = Json::parse_value(&mut chars);
+            assert_eq!(chars.next(), None);
+            json
+        }
+
+        fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Json {
+            match chars.next().unwrap() {
+                '"' => Json::String(chars.by_ref().take_while(|c| *c != '"').collect()),
+                open @ ('[' | '{') => {
+                    let mut items = Vec::new();
+                    let close = if open == '[' { ']' } else { '}' };
+                    while chars.next_if_eq(&close).is_none() {
+                        chars.next_if_eq(&',');
+                        let key = match open {
+                            '{' => match Json::parse_value(chars) {
+                                Json::String(key) => key,
+                                other => panic!("JSON key {:?}", other),
+                            },
+                            _ => String::new(),
+                        };
+                        if open == '{' {
+                            assert_eq!(chars.next(), Some(':'));
+                        }
+                        items.push((key, Json::parse_value(chars)));
+                    }
+                    match open {
+                        '[' => Json::Array(items.into_iter().map(|(_, v)| v).collect()),
+                        _ => Json::Object(items),
+                    }
+                },
+                first => {
+                    let mut token = first.to_string();
+                    while let Some(c) = chars.next_if(|c| !",]}".contains(*c)) {
+                        token.push(c);
+                    }
+                    match token.as_str() {
+                        "true" => Json::Bool(true),
+                        "false" => Json::Bool(false),
+                        number => Json::Number(number.parse().unwrap()),
+                    }
+                },
+            }
+        }
+
+        fn get(&self, key: &str) -> Option<&Json> {
+            match self {
+                Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+                _ => None,
+            }
+        }
+
+        fn items(&self) -> &[Json] {
+            match self {
+                Json::Array(items) => items,
+                other => panic!("JSON {:?} is not an array", other),
+            }
+        }
+
+        fn number(&self) -> f64 {
+            match self {
+                Json::Number(n) => *n,
+                other => panic!("JSON {:?} is not a number", other),
+            }
+        }
+    }
+
+    fn base64_decode(text: &str) -> Vec<u8> {
+        const ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut bytes = Vec::new();
+        for chunk in text.as_bytes().chunks(4) {
+            let digits: Vec<u32> = chunk
+                .iter()
+                .take_while(|c| **c != b'=')
+                .map(|c| ALPHABET.find(*c as char).unwrap() as u32)
+                .collect();
+            let n = digits.iter().enumerate().fold(0, |n, (i, d)| n | d << (18 - 6 * i));
+            bytes.extend((0..digits.len() - 1).map(|i| (n >> (16 - 8 * i)) as u8));
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_mesh_write_gltf() {
+        let grey = Voxel::from_rgba(&[128, 128, 128, 255]);
+        let mut grid = Grid::new(2, 1, 1);
+        *grid.get_mut(0, 0, 0) = grey;
+        *grid.get_mut(1, 0, 0) = grey;
+        let mut bytes = Vec::new();
+        mesh::write_gltf(&grid, &mut bytes).unwrap();
+        let json = Json::parse(&String::from_utf8(bytes).unwrap());
+        let version = json.get("asset").and_then(|asset| asset.get("version"));
+        assert_eq!(version, Some(&Json::String("2.0".to_string())));
+        // Ten exposed faces of four vertices and six indices each
+        let accessors = json.get("accessors").unwrap().items();
+        let counts: Vec<f64> = accessors.iter().map(|a| a.get("count").unwrap().number()).collect();
+        assert_eq!(counts, vec![40.0, 40.0, 40.0, 60.0]);
+        let bounds = ["min", "max"].map(|key| {
+            accessors[0].get(key).unwrap().items().iter().map(Json::number).collect::<Vec<_>>()
+        });
+        assert_eq!(bounds, [vec![0.0, 0.0, 0.0], vec![2.0, 1.0, 1.0]]);
+        let buffer = &json.get("buffers").unwrap().items()[0];
+        let views = json.get("bufferViews").unwrap().items();
+        let viewed: f64 = views.iter().map(|v| v.get("byteLength").unwrap().number()).sum();
+        assert_eq!(buffer.get("byteLength").unwrap().number(), viewed);
+        assert_eq!(viewed, (40 * 40 + 60 * 4) as f64);
+        let uri = match buffer.get("uri") {
+            Some(Json::String(uri)) => uri.strip_prefix("data:application/octet-stream;base64,"),
+            _ => None,
+        };
+        let data = base64_decode(uri.unwrap());
+        assert_eq!(data.len() as f64, viewed);
+        // COLOR_0 holds linear floats, so sRGB grey 128 is about 0.216
+        let colors = views[2].get("byteOffset").unwrap().number() as usize;
+        let red = f32::from_le_bytes(data[colors..colors + 4].try_into().unwrap());
+        let alpha = f32::from_le_bytes(data[colors + 12..colors + 16].try_into().unwrap());
+        assert!((red - 0.2158).abs() < 0.001);
+        assert_eq!(alpha, 1.0);
+    }
+
+    #[test]
+    fn test_mesh_write_gltf_empty() {
+        let mut bytes = Vec::new();
+        mesh::write_gltf(&Grid::new(2, 2, 2), &mut bytes).unwrap();
+        let json = Json::parse(&String::from_utf8(bytes).unwrap());
+        assert_eq!(json.get("scenes").unwrap().items(), [Json::Object(Vec::new())]);
+        for key in ["meshes", "buffers", "bufferViews", "accessors"] {
+            assert_eq!(json.get(key), None);
+        }
+    }
+
+    #[test]
+    fn test_mesh_emissive() {
+        let mut grid = Grid::new(2, 1, 1);
+        let mut glowing = RED_VOXEL;
+        glowing.set_emissive(true);
+        *grid.get_mut(0, 0, 0) = glowing;
+        *grid.get_mut(1, 0, 0) = Voxel::from_rgba(&[0, 0, 255, 255]);
+        let mesh = mesh::Mesh::from_grid(&grid);
+        assert_eq!(mesh.emissive.len(), mesh.colors.len());
+        for (color, emissive) in mesh.colors.iter().zip(&mesh.emissive) {
+            if *color == RED {
+                assert_eq!(*emissive, RED);
+            } else {
+                assert_eq!(*emissive, [0; 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vox_models_names_round_trip() {
+        let ground = gen_test_road_edge();
+        let mut prop = Grid::new(1, 1, 2);
+        *prop.get_mut(0, 0, 1) = RED_VOXEL;
+        let bytes = vox::encode_models_layered(&[
+            ("ground", vec![(&ground, [0, 0, 0])]),
+            ("props", vec![(&prop, [1, 2, 3])]),
+        ])
+        .unwrap();
+        let mut models = vox::decode_models(&bytes).unwrap();
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].grid, ground);
+        assert_eq!(models[1].grid, prop);
+        assert_eq!(models[1].offset, [1, 2, 3]);
+        assert_eq!(models[1].name, None);
+        models[1].name = Some("lamp".to_string());
+        models[1].shape_name = Some("lamp_shape".to_string());
+        let bytes = vox::encode_models(&models).unwrap();
+        assert_eq!(vox::validate(&bytes), Ok(()));
+        let decoded = vox::decode_models(&bytes).unwrap();
+        assert_eq!(decoded, models);
+        assert_eq!(decoded[1].name.as_deref(), Some("lamp"));
+        assert_eq!(decoded[1].shape_name.as_deref(), Some("lamp_shape"));
+    }
+
+    // A version 200 file holding a single 1x1x1 red model followed by the
+    // given chunks
+    fn vox_file(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut model = vec![(b"SIZE", [1u32, 1, 1].map(u32::to_le_bytes).concat())];
+        model.push((b"XYZI", [1u32.to_le_bytes(), [0, 0, 0, 1]].concat()));
+        let rgba = (b"RGBA", [RED, [0; 4]].concat().repeat(128));
+        let mut children = Vec::new();
+        for (id, content) in model.iter().chain(chunks).chain([&rgba]) {
+            children.extend_from_slice(*id);
+            children.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            children.extend_from_slice(&[0; 4]);
+            children.extend_from_slice(content);
+        }
+        let mut bytes = b"VOX ".to_vec();
+        bytes.extend_from_slice(&200u32.to_le_bytes());
+        bytes.extend_from_slice(b"MAIN");
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&(children.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&children);
+        bytes
+    }
+
+    fn vox_words(words: &[i32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    // An nTRN node with an optional _t frame attribute
+    fn vox_transform(id: i32, child: i32, t: Option<&str>) -> Vec<u8> {
+        let mut content = vox_words(&[id, 0, child, -1, 0, 1]);
+        match t {
+            None => content.extend_from_slice(&vox_words(&[0])),
+            Some(t) => {
+                content.extend_from_slice(&vox_words(&[1, 2]));
+                content.extend_from_slice(b"_t");
+                content.extend_from_slice(&vox_words(&[t.len() as i32]));
+                content.extend_from_slice(t.as_bytes());
+            },
+        }
+        content
+    }
+
+    #[test]
+    fn test_vox_decode_models_rejects_cycles() {
+        let shape = (b"nSHP", vox_words(&[2, 0, 1, 0, 0]));
+        let bytes = vox_file(&[(b"nTRN", vox_transform(0, 2, Some("1 2 3"))), shape.clone()]);
+        assert_eq!(vox::decode_models(&bytes).unwrap()[0].offset, [1, 2, 3]);
+
+        let bytes = vox_file(&[(b"nTRN", vox_transform(0, 0, None))]);
+        let error = vox::decode_models(&bytes).unwrap_err();
+        assert_eq!(error.to_string(), vox::VoxError::SceneCycle(0).to_string());
+
+        let group = (b"nGRP", vox_words(&[1, 0, 3, 2, 1, 1]));
+        let bytes = vox_file(&[(b"nTRN", vox_transform(0, 1, None)), group, shape]);
+        let error = vox::decode_models(&bytes).unwrap_err();
+        assert_eq!(error.to_string(), vox::VoxError::SceneCycle(1).to_string());
+    }
+
+    #[test]
+    fn test_vox_decode_models_translation_overflow() {
+        let bytes = vox_file(&[
+            (b"nTRN", vox_transform(0, 1, Some("2000000000 0 0"))),
+            (b"nTRN", vox_transform(1, 2, Some("2000000000 0 0"))),
+            (b"nSHP", vox_words(&[2, 0, 1, 0, 0])),
+        ]);
+        let error = vox::decode_models(&bytes).unwrap_err();
+        assert_eq!(error.to_string(), vox::VoxError::TranslationOverflow.to_string());
+    }
+
+    #[test]
+    fn test_vox_decode_indexed_scene() {
+        let shape = |id| (b"nSHP", vox_words(&[id, 0, 1, 0, 0]));
+        let bytes = vox_file(&[
+            (b"nTRN", vox_transform(0, 1, None)),
+            (b"nGRP", vox_words(&[1, 0, 2, 2, 4])),
+            (b"nTRN", vox_transform(2, 3, None)),
+            shape(3),
+            (b"nTRN", vox_transform(4, 5, Some("3 0 0"))),
+            shape(5),
+        ]);
+        let grid = vox::decode(&bytes).unwrap();
+        let (indices, palette) = vox::decode_indexed(&bytes).unwrap();
+        assert_eq!((indices.width(), indices.depth(), indices.height()), (4, 1, 1));
+        for (x, y, z, i) in indices.enumerate_cells() {
+            assert_eq!(grid.get(x, y, z).as_rgba(), palette[*i as usize]);
+        }
+        assert_eq!(indices.positions_of(|i| *i == 1), vec![(0, 0, 0), (3, 0, 0)]);
+    }
+
+    #[test]
+    fn test_vox_decode_rejects_oversized_scene() {
+        let shape = |id| (b"nSHP", vox_words(&[id, 0, 1, 0, 0]));
+        let bytes = vox_file(&[
+            (b"nTRN", vox_transform(0, 1, None)),
+            (b"nGRP", vox_words(&[1, 0, 2, 2, 4])),
+            (b"nTRN", vox_transform(2, 3, Some("2000000000 0 0"))),
+            shape(3),
+            (b"nTRN", vox_transform(4, 5, Some("-2000000000 0 0"))),
+            shape(5),
+        ]);
+        assert_eq!(vox::decode_models(&bytes).unwrap().len(), 2);
+        let error = vox::decode(&bytes).unwrap_err();
+        let expected = vox::VoxError::SceneTooLarge([4_000_000_001, 1, 1]);
+        assert_eq!(error.to_string(), expected.to_string());
+
+        // Two specks at opposite corners of a 2048^3 box
+        let bytes = vox_file(&[
+            (b"nTRN", vox_transform(0, 1, None)),
+            (b"nGRP", vox_words(&[1, 0, 2, 2, 4])),
+            (b"nTRN", vox_transform(2, 3, None)),
+            shape(3),
+            (b"nTRN", vox_transform(4, 5, Some("2047 2047 2047"))),
+            shape(5),
+        ]);
+        let error = vox::decode(&bytes).unwrap_err();
+        let expected = vox::VoxError::SceneTooLarge([2048, 2048, 2048]);
+        assert_eq!(error.to_string(), expected.to_string());
+        assert!(vox::decode_indexed(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_vox_decode_rejects_oversized_model() {
+        let mut bytes = vox_file(&[]);
+        assert!(vox::decode(&bytes).is_ok());
+        // The SIZE content follows the 20 byte file header and its own 12
+        bytes[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        let expected = vox::VoxError::ModelTooLarge([u32::MAX, 1, 1]).to_string();
+        assert_eq!(vox::decode(&bytes).unwrap_err().to_string(), expected);
+        assert_eq!(vox::decode_indexed(&bytes).unwrap_err().to_string(), expected);
+        let mut cursor = std::io::Cursor::new(&bytes);
+        assert_eq!(vox::decode_reader(&mut cursor).unwrap_err().to_string(), expected);
+    }
+
+    #[test]
+    fn test_vox_decode_reader_caps_chunk_sizes() {
+        let mut bytes = vox_file(&[]);
+        // The SIZE chunk's content size follows its id at byte 20
+        bytes[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut cursor = std::io::Cursor::new(&bytes);
+        let error = vox::decode_reader(&mut cursor).unwrap_err();
+        let expected = vox::VoxError::ChunkTooLarge {
+            id: "SIZE",
+            declared: u32::MAX as usize,
+            limit: 12,
+        };
+        assert_eq!(error.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn test_vox_validate_deep_nesting() {
+        let depth = 100_000;
+        let mut bytes = b"VOX ".to_vec();
+        bytes.extend_from_slice(&150u32.to_le_bytes());
+        for level in 0..depth {
+            let id = if level == 0 { b"MAIN" } else { b"NEST" };
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&(12 * (depth - 1 - level) as u32).to_le_bytes());
+        }
+        assert_eq!(vox::validate(&bytes), Ok(()));
+        bytes.pop();
+        assert!(matches!(vox::validate(&bytes), Err(vox::VoxError::Truncated { .. })));
+    }
+
+    #[test]
+    fn test_grid_occupancy_bitset() {
+        let mut grid = Grid::new(8, 8, 5);
+        *grid.get_mut(5, 2, 4) = RED_VOXEL;
+        let occupancy = grid.occupancy_bitset(4);
+        assert_eq!((occupancy.width(), occupancy.depth(), occupancy.height()), (2, 2, 2));
+        for (x, y, z, o) in occupancy.enumerate_cells() {
+            assert_eq!(*o, u8::from((x, y, z) == (1, 0, 1)));
+        }
+        let bits = bitgrid::BitGrid::from(&occupancy);
+        assert!(bits.get(1, 0, 1));
+        assert!(!bits.get(0, 0, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "BitGrid len overflows usize")]
+    fn test_bitgrid_len_overflow() {
+        bitgrid::BitGrid::new(u32::MAX, u32::MAX, u32::MAX);
+    }
+
+    #[test]
+    fn test_bitgrid_set_get() {
+        let mut bits = bitgrid::BitGrid::new(3, 2, 2);
+        assert!(!bits.get(2, 1, 1));
+        bits.set(2, 1, 1, true);
+        bits.set(0, 1, 0, true);
+        assert!(bits.get(2, 1, 1));
+        assert!(bits.get(0, 1, 0));
+        assert!(!bits.get(1, 1, 1));
+        bits.set(2, 1, 1, false);
+        assert!(!bits.get(2, 1, 1));
+    }
+
+    #[test]
+    fn test_bitgrid_enumerate_set() {
+        let mut bits = bitgrid::BitGrid::new(5, 3, 4);
+        let set = [(0, 0, 0), (4, 0, 0), (3, 1, 0), (1, 2, 2), (4, 2, 3)];
+        for (x, y, z) in set {
+            bits.set(x, y, z, true);
+        }
+        assert_eq!(bits.enumerate_set().collect::<Vec<_>>(), set);
+        let mask = gen_test_road_edge().color_mask([108, 108, 127, 255]);
+        let bits = bitgrid::BitGrid::from(&mask);
+        let expected: Vec<_> =
+            mask.enumerate_cells().filter(|c| *c.3 != 0).map(|(x, y, z, _)| (x, y, z)).collect();
+        assert_eq!(bits.enumerate_set().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_octree_uniform_collapses() {
+        let mut grid = Grid::new(4, 4, 4);
+        grid.map_in_place(|v| *v = RED_VOXEL);
+        let tree = octree::Octree::from_grid(&grid);
+        assert_eq!(*tree.root(), octree::Node::Leaf(RED_VOXEL));
+        for (x, y, z, v) in grid.enumerate_cells() {
+            assert_eq!(tree.get(x, y, z), v);
+        }
+    }
+
+    #[test]
+    fn test_octree_get() {
+        let grid = gen_test_road_edge();
+        let tree = octree::Octree::from_grid(&grid);
+        assert!(matches!(tree.root(), octree::Node::Branch(_)));
+        for (x, y, z, v) in grid.enumerate_cells() {
+            assert_eq!(tree.get(x, y, z), v);
+        }
+    }
+
+    #[test]
+    fn test_octree_thin_grid() {
+        // A 1024^3 cube around these must not be walked cell by cell
+        for (width, depth) in [(1000, 1), (256, 256)] {
+            let mut grid = Grid::new(width, depth, 1);
+            for (x, y, _, v) in grid.enumerate_cells_mut() {
+                if (x + y) % 3 == 0 {
+                    *v = RED_VOXEL;
+                }
+            }
+            let tree = octree::Octree::from_grid(&grid);
+            for (x, y, z, v) in grid.enumerate_cells() {
+                assert_eq!(tree.get(x, y, z), v);
+            }
+        }
+        let mut grid = Grid::new(1000, 1, 1);
+        grid.map_in_place(|v| *v = RED_VOXEL);
+        let tree = octree::Octree::from_grid(&grid);
+        assert!(matches!(tree.root(), octree::Node::Branch(_)));
+        assert_eq!(*tree.get(999, 0, 0), RED_VOXEL);
+    }
+
+    #[test]
+    fn test_grid_native_round_trip() {
+        let mut grid = Grid::<u32>::new(3, 2, 4);
+        for (id, (_, _, _, t)) in grid.enumerate_cells_mut().enumerate() {
+            *t = id as u32 * 1000;
+        }
+        let mut bytes = Vec::new();
+        grid.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 18 + 24 * 4);
+        assert_eq!(Grid::<u32>::read(&mut &bytes[..]).unwrap(), grid);
+        let error = Grid::<Voxel>::read(&mut &bytes[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(Grid::<u32>::read(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn test_grid_native_gz_round_trip() {
+        let mut grid = Grid::<u32>::new(16, 16, 16);
+        grid.map_in_place(|t| *t = 7);
+        let mut raw = Vec::new();
+        grid.write(&mut raw).unwrap();
+        let mut gz = Vec::new();
+        grid.write_gz(&mut gz).unwrap();
+        assert!(gz.len() < raw.len());
+        assert_eq!(Grid::<u32>::read_gz(&mut &gz[..]).unwrap(), grid);
+    }
+
+    #[test]
+    fn test_grid_scale_nearest() {
+        let mut grid = Grid::<u32>::new(3, 3, 3);
+        for (x, y, z, t) in grid.enumerate_cells_mut() {
+            *t = x + 3 * y + 9 * z;
+        }
+        let up = grid.scale_nearest(5, 5, 5);
+        assert_eq!((up.width(), up.depth(), up.height()), (5, 5, 5));
+        assert_eq!(*up.get(0, 0, 0), 0);
+        assert_eq!(*up.get(2, 2, 2), 13);
+        assert_eq!(*up.get(4, 0, 0), 2);
+        assert_eq!(*up.get(1, 3, 4), 2 * 9 + 2 * 3);
+        let down = grid.scale_nearest(2, 2, 2);
+        assert_eq!(*down.get(0, 0, 0), 0);
+        assert_eq!(*down.get(1, 1, 1), 26);
+        assert_eq!(grid.scale_nearest(3, 3, 3), grid);
+    }
+
+    #[test]
+    fn test_grid_rotate_xy() {
+        let mut grid = Grid::<u32>::new(2, 3, 2);
+        for (x, y, z, t) in grid.enumerate_cells_mut() {
+            *t = x + 2 * y + 6 * z;
+        }
+        let rotated = grid.rotate_xy(&Rotation::R90);
+        assert_eq!((rotated.width(), rotated.depth(), rotated.height()), (3, 2, 2));
+        for (x, y, z, t) in grid.enumerate_cells() {
+            assert_eq!(rotated.get(2 - y, x, z), t);
+        }
+        let back = rotated.rotate_xy(&Rotation::R270);
+        assert_eq!(back, grid);
+        let half = grid.rotate_xy(&Rotation::R180);
+        assert_eq!(*half.get(0, 0, 1), *grid.get(1, 2, 1));
+    }
+
+    #[test]
+    fn test_voxel_rotated_z_full_turn() {
+        for size in [3, 4] {
+            let mut grid = Grid::<u32>::new(size, size, size);
+            for (x, y, z, t) in grid.enumerate_cells_mut() {
+                *t = 1 + x + size * y + size * size * z;
+            }
+            let turned = grid
+                .rotated_z(&Rotation::R90)
+                .rotated_z(&Rotation::R90)
+                .rotated_z(&Rotation::R90)
+                .rotated_z(&Rotation::R90);
+            assert_eq!(turned, grid);
+            let rotated = grid.rotated_z(&Rotation::R90);
+            for (x, y, z, t) in grid.enumerate_cells() {
+                assert_eq!(rotated.get(size - 1 - y, x, z), t);
+            }
+            assert_eq!(grid.rotated_z(&Rotation::R180), rotated.rotated_z(&Rotation::R90));
+        }
+    }
+
+    #[test]
+    fn test_grid_distinct_values() {
+        let mut grid = Grid::<u32>::new(3, 3, 3);
+        *grid.get_mut(0, 0, 0) = 1;
+        *grid.get_mut(1, 0, 0) = 2;
+        *grid.get_mut(2, 0, 0) = 2;
+        assert_eq!(grid.distinct_values(), 3);
+        let mut voxels = gen_test_road_edge();
+        assert_eq!(voxels.distinct_colors(), 3);
+        voxels.get_mut(0, 0, 0).set_emissive(true);
+        assert_eq!(voxels.distinct_values(), 4);
+        assert_eq!(voxels.distinct_colors(), 3);
+    }
+
+    #[test]
+    fn test_grid_mirror() {
+        let mut grid = Grid::<u32>::new(2, 3, 4);
+        for (x, y, z, t) in grid.enumerate_cells_mut() {
+            *t = x + 2 * y + 6 * z;
+        }
+        let x = grid.mirror(Axis::X);
+        let y = grid.mirror(Axis::Y);
+        let z = grid.mirror(Axis::Z);
+        for (gx, gy, gz, t) in grid.enumerate_cells() {
+            assert_eq!(x.get(1 - gx, gy, gz), t);
+            assert_eq!(y.get(gx, 2 - gy, gz), t);
+            assert_eq!(z.get(gx, gy, 3 - gz), t);
+        }
+        assert_eq!(x.mirror(Axis::X), grid);
+    }
+
+    #[test]
+    fn test_grid_rotate() {
+        let mut grid = Grid::<u32>::new(2, 3, 4);
+        for (x, y, z, t) in grid.enumerate_cells_mut() {
+            *t = x + 2 * y + 6 * z;
+        }
+        let x = grid.rotate(Axis::X, &Rotation::R90);
+        assert_eq!((x.width(), x.depth(), x.height()), (2, 4, 3));
+        let y = grid.rotate(Axis::Y, &Rotation::R90);
+        assert_eq!((y.width(), y.depth(), y.height()), (4, 3, 2));
+        let z = grid.rotate(Axis::Z, &Rotation::R90);
+        assert_eq!(z, grid.rotated_z(&Rotation::R90));
+        for (gx, gy, gz, t) in grid.enumerate_cells() {
+            // y turns toward z about x, z toward x about y
+            assert_eq!(x.get(gx, 3 - gz, gy), t);
+            assert_eq!(y.get(gz, gy, 1 - gx), t);
+        }
+        for axis in [Axis::X, Axis::Y, Axis::Z] {
+            let half = grid.rotate(axis, &Rotation::R180);
+            assert_eq!(half, grid.rotate(axis, &Rotation::R90).rotate(axis, &Rotation::R90));
+            assert_eq!(grid.rotate(axis, &Rotation::R270).rotate(axis, &Rotation::R90), grid);
+        }
+    }
+
+    #[test]
+    fn test_grid_content_hash() {
+        let a = gen_test_road_edge();
+        let mut b = gen_test_road_edge();
+        assert_eq!(a.content_hash(), b.content_hash());
+        *b.get_mut(1, 1, 1) = RED_VOXEL;
+        assert_ne!(a.content_hash(), b.content_hash());
+        let flat = Grid::<u8>::new(4, 1, 1);
+        assert_ne!(flat.content_hash(), Grid::<u8>::new(1, 4, 1).content_hash());
+    }
+
+    #[test]
+    fn test_grid_apply_directional_light() {
+        let mut grid = Grid::new(4, 4, 2);
+        grid.map_in_place(|v| *v = Voxel::from_rgba(&[200, 200, 200, 255]));
+        let lit = grid.apply_directional_light([0.0, 0.0, 1.0], 0.25);
+        let top = lit.get(1, 1, 1).as_rgba()[0];
+        let side = lit.get(0, 1, 0).as_rgba()[0];
+        assert_eq!(top, 200);
+        assert_eq!(side, 50);
+        assert!(top > side);
+        assert_eq!(lit.get(1, 1, 1).as_rgba()[3], 255);
+    }
+
+    #[test]
+    fn test_grid_to_y_up() {
+        let mut grid: Grid<u32> = Grid::new(2, 3, 4);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            *v = x + 10 * y + 100 * z;
+        }
+        let y_up = grid.to_y_up();
+        assert_eq!((y_up.width(), y_up.depth(), y_up.height()), (2, 4, 3));
+        for (x, y, z, v) in grid.enumerate_cells() {
+            assert_eq!(y_up.get(x, z, 2 - y), v);
+        }
+        assert_eq!(y_up.to_z_up(), grid);
+    }
+
+    #[test]
+    fn test_grid_to_y_up_keeps_handedness() {
+        // A twisted path with no mirror symmetry
+        let mut grid: Grid<u8> = Grid::new(2, 2, 2);
+        for (x, y, z) in [(0, 0, 0), (1, 0, 0), (1, 1, 0), (1, 1, 1)] {
+            *grid.get_mut(x, y, z) = 1;
+        }
+        assert!(grid.mirror(Axis::X).is_rotation_of(&grid).is_none());
+        assert!(grid.to_y_up().is_rotation_of(&grid).is_some());
+        assert!(grid.to_y_up().to_z_up().is_rotation_of(&grid).is_some());
+    }
+
+    #[test]
+    fn test_grid_retain() {
+        let mut grid = gen_test_road_edge();
+        *grid.get_mut(1, 1, 1) = Voxel::from_rgba(&[1, 2, 3, 0]);
+        *grid.get_mut(2, 2, 2) = Voxel::from_rgba(&[9, 9, 9, 0]);
+        let before = grid.clone();
+        grid.retain(|_, _, _, v| v.as_rgba()[3] > 0);
+        for (x, y, z, v) in grid.enumerate_cells() {
+            if before.get(x, y, z).as_rgba()[3] > 0 {
+                assert_eq!(v, before.get(x, y, z));
+            } else {
+                assert_eq!(*v, Voxel::from_rgba(&[0; 4]));
+            }
+        }
+        assert_eq!(grid.diff(&before).len(), 2);
+    }
+
+    #[test]
+    fn test_grid_count_by() {
+        let mut grid = gen_test_road_edge();
+        *grid.get_mut(0, 0, 0) = Voxel::from_rgba(&[0; 4]);
+        *grid.get_mut(1, 0, 0) = Voxel::from_rgba(&[0; 4]);
+        *grid.get_mut(2, 0, 0) = Voxel::from_rgba(&[50, 50, 50, 128]);
+        let counts = grid.count_by(|v| v.as_rgba()[3]);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[&0], 2);
+        assert_eq!(counts[&128], 1);
+        assert_eq!(counts[&255], 24);
+    }
+
+    #[test]
+    fn test_grid_slice() {
+        let mut grid: Grid<u32> = Grid::new(3, 3, 3);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            *v = x + 10 * y + 100 * z;
+        }
+        let plane = grid.slice(Axis::X, 1);
+        assert_eq!(plane.len(), 9);
+        for (y, z, v) in &plane {
+            assert_eq!(**v, 1 + 10 * y + 100 * z);
+        }
+        assert_eq!(plane[1], (1, 0, &11));
+        let plane = grid.slice(Axis::Z, 2);
+        assert_eq!(plane.len(), 9);
+        assert!(plane.iter().all(|(x, y, v)| **v == x + 10 * y + 200));
+        assert!(grid.slice(Axis::Y, 3).is_empty());
+    }
+
+    #[test]
+    fn test_visually_eq() {
+        let mut a = gen_test_road_edge();
+        *a.get_mut(1, 1, 1) = Voxel::from_rgba(&[1, 2, 3, 0]);
+        let mut b = a.clone();
+        *b.get_mut(1, 1, 1) = Voxel::from_rgba(&[0; 4]);
+        assert_ne!(a, b);
+        assert!(a.visually_eq(&b));
+        *b.get_mut(0, 0, 0) = Voxel::from_rgba(&[0, 0, 0, 255]);
+        assert!(!a.visually_eq(&b));
+        assert!(!a.visually_eq(&Grid::new(3, 3, 2)));
+    }
+
+    #[test]
+    fn test_grid_resized_centered() {
+        let mut grid: Grid<u8> = Grid::new(2, 2, 2);
+        grid.map_in_place(|v| *v = 7);
+        let centered = grid.resized_centered(4, 4, 4, 1);
+        for (x, y, z, v) in centered.enumerate_cells() {
+            let inside = [x, y, z].iter().all(|c| (1..=2).contains(c));
+            assert_eq!(*v, if inside { 7 } else { 1 });
+        }
+        let mut grid: Grid<u32> = Grid::new(4, 1, 1);
+        for (x, _, _, v) in grid.enumerate_cells_mut() {
+            *v = x;
+        }
+        let cropped = grid.resized_centered(2, 1, 1, 0);
+        assert_eq!((*cropped.get(0, 0, 0), *cropped.get(1, 0, 0)), (1, 2));
+    }
+
+    #[test]
+    fn test_grid_zip_with() {
+        let grid = gen_test_road_edge();
+        let light: Vec<u32> = (0..27).collect();
+        let zipped: Vec<_> = grid.zip_with(&light).collect();
+        assert_eq!(zipped.len(), 27);
+        for (p, v, l) in zipped {
+            assert_eq!(v, grid.get(p.0, p.1, p.2));
+            assert_eq!(grid.id(p.0, p.1, p.2), Some(*l as usize));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_grid_zip_with_length_mismatch() {
+        let grid = gen_test_road_edge();
+        let _ = grid.zip_with(&[0u8; 26]);
+    }
+
+    #[test]
+    fn test_boundary() {
+        let mut grid = Grid::new(5, 5, 5);
+        grid.map_region((1, 1, 1), (3, 3, 3), |_, _, _, v| *v = RED_VOXEL);
+        assert!(grid.boundary().is_empty());
+        *grid.get_mut(4, 2, 2) = RED_VOXEL;
+        *grid.get_mut(2, 0, 3) = RED_VOXEL;
+        assert_eq!(grid.boundary(), [(4, 2, 2), (2, 0, 3)]);
+    }
+
+    #[test]
+    fn test_fill_gradient() {
+        let corners = [
+            [0, 0, 0, 255],
+            [40, 0, 0, 255],
+            [0, 80, 0, 255],
+            [40, 80, 0, 255],
+            [0, 0, 160, 255],
+            [40, 0, 160, 255],
+            [0, 80, 160, 255],
+            [200, 240, 248, 255],
+        ];
+        let mut grid = Grid::new(3, 3, 3);
+        grid.fill_gradient(corners);
+        for (i, corner) in corners.iter().enumerate() {
+            let i = i as u32;
+            assert_eq!(grid.get((i & 1) * 2, (i >> 1 & 1) * 2, (i >> 2) * 2).as_rgba(), corner);
+        }
+        let average = std::array::from_fn::<u8, 4, _>(|c| {
+            (corners.iter().map(|corner| corner[c] as u32).sum::<u32>() / 8) as u8
+        });
+        assert_eq!(grid.get(1, 1, 1).as_rgba(), average);
+    }
+
+    #[test]
+    fn test_voxel_rotate_hue() {
+        let red = Voxel::from_rgba(&[255, 0, 0, 200]);
+        assert_eq!(red.to_hsv(), (0.0, 1.0, 1.0));
+        assert_eq!(red.rotate_hue(120.0).as_rgba(), [0, 255, 0, 200]);
+        assert_eq!(red.rotate_hue(-120.0).as_rgba(), [0, 0, 255, 200]);
+        let mut glowing = red;
+        glowing.set_emissive(true);
+        assert!(glowing.rotate_hue(90.0).is_emissive());
     }
 
     #[test]
-    fn test_voxel_from_rgba() {
-        assert_eq!(Voxel::from_rgba(&RED), RED_VOXEL);
+    fn test_voxel_hsv_round_trip() {
+        for rgba in [[108, 108, 127, 255], [90, 120, 20, 255], [120, 80, 50, 7], [0, 0, 0, 255], [255, 255, 255, 0]] {
+            let (h, s, v) = Voxel::from_rgba(&rgba).to_hsv();
+            let round_trip = Voxel::from_hsv(h, s, v, rgba[3]);
+            for (a, b) in round_trip.as_rgba().iter().zip(rgba) {
+                assert!(a.abs_diff(b) <= 1, "{:?} became {:?}", rgba, round_trip);
+            }
+        }
     }
 
     #[test]
-    fn test_voxel_as_slice() {
-        assert_eq!(RED_VOXEL.as_slice(), RED_SLICE);
+    fn test_grid_zero_dimensions() {
+        for (width, depth, height) in [(0, 3, 3), (0, 0, 0), (3, 3, 0)] {
+            let mut grid: Grid<Voxel> = Grid::new(width, depth, height);
+            assert_eq!(grid.cell_count(), 0);
+            assert_eq!(grid.enumerate_cells().count(), 0);
+            assert_eq!(grid.enumerate_cells_mut().count(), 0);
+            assert_eq!(grid.enumerate_morton().count(), 0);
+            assert_eq!(grid.layers_mut().count(), 0);
+            for rotation in [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270] {
+                let rotated = grid.rotated_z(&rotation);
+                assert_eq!(rotated.cell_count(), 0);
+                assert_eq!(rotated.width() * rotated.depth(), width * depth);
+            }
+            let bytes = vox::encode(&grid).unwrap();
+            vox::validate(&bytes).unwrap();
+            let decoded = vox::decode(&bytes).unwrap();
+            assert_eq!((decoded.width(), decoded.depth(), decoded.height()), (width, depth, height));
+        }
     }
 
     #[test]
-    fn test_voxel_from_slice() {
-        assert_eq!(*<Voxel>::from_slice(&RED_SLICE), RED_VOXEL);
+    fn test_face_signatures() {
+        let grid = gen_test_road_edge();
+        let faces = grid.face_signatures();
+        assert!(faces.iter().all(|face| face.len() == 9));
+        let (grey, green, brown) = ([108, 108, 127, 255], [90, 120, 20, 255], [120, 80, 50, 255]);
+        assert_eq!(faces[4], [grey, grey, green, grey, grey, green, grey, grey, green]);
+        assert_eq!(faces[5], [brown; 9]);
+        assert_eq!(faces[1][6..], [grey, grey, grey]);
     }
 
     #[test]
-    fn test_voxel_from_slice_mut() {
-        let mut array = [0u8; 8];
-        array.copy_from_slice(&RED_SLICE);
-        let voxel = <Voxel>::from_slice_mut(&mut array);
-        voxel.0[1] = 255;
-        assert_eq!(*voxel, Voxel([255, 255, 0, 255, 0, 0, 0, 0]));
+    fn test_from_image_layer() {
+        let pixels = [[1, 0, 0, 255], [2, 0, 0, 255], [3, 0, 0, 255], [4, 0, 0, 0]];
+        let grid = Grid::from_image_layer(&pixels, 2, 2);
+        assert_eq!((grid.width(), grid.depth(), grid.height()), (2, 2, 1));
+        assert_eq!(grid.get(1, 0, 0).as_rgba(), pixels[1]);
+        assert_eq!(grid.get(0, 1, 0).as_rgba(), pixels[2]);
+        assert_eq!(grid.get(1, 1, 0).as_rgba(), pixels[3]);
     }
 
     #[test]
-    fn test_grid() {
-        let grid_width = 3;
-        let grid_depth = 3;
-        let grid_height = 3;
-        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+    #[should_panic]
+    fn test_from_image_layer_length_mismatch() {
+        Grid::from_image_layer(&[[0; 4]; 3], 2, 2);
+    }
 
-        let mut order = 0;
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    order += 1;
-                    *grid.get_mut(x, y, z) = order;
-                }
-            }
+    #[test]
+    fn test_grid_extend_z() {
+        let mut grid: Grid<u32> = Grid::new(2, 2, 2);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            *v = x + 10 * y + 100 * z + 1;
         }
-        order = 0;
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    order += 1;
-                    let value = grid.get(x, y, z);
-                    assert_eq!(*value, order);
-                }
-            }
+        let before = grid.clone();
+        grid.extend_z(1, 7);
+        assert_eq!(grid.height(), 3);
+        assert_eq!(grid.cell_count(), 12);
+        for (x, y, z, v) in before.enumerate_cells() {
+            assert_eq!(grid.get(x, y, z), v);
         }
+        assert!(grid.enumerate_cells_in_z(2..3).all(|(.., v)| *v == 7));
     }
 
     #[test]
-    fn test_grid_voxel() {
-        let grid_width = 3;
-        let grid_depth = 3;
-        let grid_height = 3;
-        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+    fn test_grid_truncate_z() {
+        let mut grid: Grid<u32> = Grid::new(2, 2, 3);
+        for (.., z, v) in grid.enumerate_cells_mut() {
+            *v = z + 1;
+        }
+        grid.truncate_z(2);
+        assert_eq!(grid.height(), 2);
+        assert_eq!(grid.cell_count(), 8);
+        assert!(!grid.contains(0, 0, 2));
+        assert!(grid.enumerate_cells().all(|(.., z, v)| *v == z + 1));
+        assert_eq!(grid.distinct_values(), 2);
+    }
 
-        let red = [255, 0, 0, 255];
-        let voxel = Voxel::from_rgba(&red);
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    *grid.get_mut(x, y, z) = voxel;
-                }
-            }
+    #[test]
+    #[should_panic]
+    fn test_grid_truncate_z_taller() {
+        let mut grid: Grid<u32> = Grid::new(2, 2, 3);
+        grid.truncate_z(4);
+    }
+
+    #[test]
+    fn test_grid_cached_layer_index() {
+        let mut grid: Grid<u32> = Grid::new(5, 3, 4);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            *v = x + 10 * y + 100 * z;
         }
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    let value = grid.get(x, y, z);
-                    assert_eq!(value.as_rgba(), red);
-                }
+        let mut bytes = Vec::new();
+        grid.write(&mut bytes).unwrap();
+        let read = Grid::<u32>::read(&mut &bytes[..]).unwrap();
+        let mut extended = grid.clone();
+        extended.extend_z(2, 0);
+        for g in [&grid, &read, &extended] {
+            for (x, y, z, v) in grid.enumerate_cells() {
+                let id = x as usize + 5 * y as usize + 15 * z as usize;
+                assert_eq!(g.id(x, y, z), Some(id));
+                assert_eq!(g.coordinate(id), Some((x, y, z)));
+                assert_eq!(g.get(x, y, z), v);
+                // The cached byte strides agree with scaling the cell id
+                assert_eq!(g.indices(x, y, z), Some(g.indices_unchecked(id)));
             }
         }
+        assert_eq!(extended.indices(0, 0, 6), None);
+        let bytes = grid.reinterpret::<u8>().unwrap();
+        assert_eq!(bytes.id(3, 2, 1), Some(3 + 20 * 2 + 60));
+        assert_eq!(bytes.coordinate(3 + 20 * 2 + 60), Some((3, 2, 1)));
+        for (x, y, z, _) in bytes.enumerate_cells() {
+            assert_eq!(bytes.indices(x, y, z), bytes.id(x, y, z).map(|id| id..id + 1));
+        }
     }
 
     #[test]
-    fn test_grid_enumerate_cells() {
-        let grid_width = 3;
-        let grid_depth = 3; 
-        let grid_height = 3;
-        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    let color = [x as u8, y as u8, z as u8, 255];
-                    let voxel = Voxel::from_rgba(&color);
-                    *grid.get_mut(x, y, z) = voxel;
-                }
-            }
+    fn test_grid_swap_layers() {
+        let mut grid: Grid<u32> = Grid::new(2, 3, 4);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            *v = x + 10 * y + 100 * z;
         }
+        let before = grid.clone();
+        grid.swap_layers(3, 0);
         for (x, y, z, v) in grid.enumerate_cells() {
-            let rgba = v.as_rgba();
-            assert_eq!(x as u8, rgba[0]);
-            assert_eq!(y as u8, rgba[1]);
-            assert_eq!(z as u8, rgba[2]);
+            let source = match z {
+                0 => 3,
+                3 => 0,
+                z => z,
+            };
+            assert_eq!(v, before.get(x, y, source));
         }
+        grid.swap_layers(2, 2);
+        grid.swap_layers(0, 3);
+        assert_eq!(grid, before);
     }
 
     #[test]
-    fn test_grid_enumerate_cells_with_ids() {
-        let grid_width = 3;
-        let grid_depth = 3; 
-        let grid_height = 3;
-        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    let color = [x as u8, y as u8, z as u8, 255];
-                    let voxel = Voxel::from_rgba(&color);
-                    *grid.get_mut(x, y, z) = voxel;
-                }
-            }
-        }
-        for (id, (x, y, z, v))in grid.enumerate_cells().enumerate() {
-            let rgba = v.as_rgba();
-            println!("{:?} {:?}", id, rgba);
-            assert_eq!(x as u8, rgba[0]);
-            assert_eq!(y as u8, rgba[1]);
-            assert_eq!(z as u8, rgba[2]);
-        }
+    fn test_grid_reverse_z() {
+        let original = gen_test_road_edge().with_border(1, RED_VOXEL).scale_nearest(5, 5, 4);
+        let mut grid = original.clone();
+        grid.reverse_z();
+        assert_eq!(grid, original.mirror(Axis::Z));
+        assert_ne!(grid, original);
+        grid.reverse_z();
+        assert_eq!(grid, original);
     }
 
     #[test]
-    fn test_grid_cell_count() {
-        let grid_width = 3;
-        let grid_depth = 3; 
-        let grid_height = 3;
-        let grid = Grid::<u32>::new(grid_width, grid_depth, grid_height);
-        assert_eq!(grid.cell_count(), 27);
+    fn test_grid_enumerate_order() {
+        let grid: Grid<u8> = Grid::new(2, 1, 3);
+        let coordinates = |order| grid.enumerate_order(order).map(|(x, y, z, _)| (x, y, z)).collect::<Vec<_>>();
+        let storage: Vec<_> = grid.enumerate_cells().map(|(x, y, z, _)| (x, y, z)).collect();
+        assert_eq!(coordinates([Axis::X, Axis::Y, Axis::Z]), storage);
+        assert_eq!(
+            coordinates([Axis::Z, Axis::Y, Axis::X]),
+            [(0, 0, 0), (0, 0, 1), (0, 0, 2), (1, 0, 0), (1, 0, 1), (1, 0, 2)]
+        );
     }
 
     #[test]
-    fn test_vox_write() {
-        let grid_width = 3;
-        let grid_depth = 3; 
-        let grid_height = 3;
-        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
-        let black = [0, 0, 0, 255];
-        let white = [255, 255, 255, 255];
-        let voxel_black = Voxel::from_rgba(&black);
-        let voxel_white = Voxel::from_rgba(&white);
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    if z == 0 {
-                        *grid.get_mut(x, y, z) = voxel_black;
-                    } else {
-                        *grid.get_mut(x, y, z) = voxel_white;
-                    }
-                }
-            }
-        }
-        let bytes = vox::encode(&grid).unwrap();
-        fs::write("test.vox", &bytes).unwrap();
+    fn test_add_colored_sphere() {
+        let mut grid = Grid::new(7, 7, 7);
+        grid.add_colored_sphere([3.0, 3.0, 3.0], 3.0, RED);
+        assert_eq!(grid.get(3, 3, 3).as_rgba(), RED);
+        let edge = grid.get(3, 3, 5).as_rgba();
+        assert_eq!(&edge[..3], &RED[..3]);
+        assert!(edge[3] > 0 && edge[3] < 255);
+        assert!(grid.get(3, 3, 4).as_rgba()[3] > edge[3]);
+        assert_eq!(grid.get(3, 3, 6).as_rgba()[3], 0);
+        assert_eq!(grid.get(0, 0, 0).as_rgba()[3], 0);
+
+        // Blends over what is already there
+        let mut grid = Grid::new(3, 3, 3);
+        grid.map_in_place(|v| *v = Voxel::from([0, 0, 255, 255]));
+        grid.add_colored_sphere([1.0, 1.0, 1.0], 2.0, RED);
+        assert_eq!(grid.get(1, 1, 1).as_rgba(), RED);
+        let blended = grid.get(1, 1, 2).as_rgba();
+        assert!(blended[0] > 0 && blended[2] > 0 && blended[3] == 255);
     }
 
     #[test]
-    fn test_vox_write_transparent_voxel() {
-        let grid_width = 3;
-        let grid_depth = 3;
-        let grid_height = 3;
-        let mut grid = Grid::new(grid_width, grid_depth, grid_height);
+    fn test_bounding_box_of_color() {
+        let grid = gen_test_road_edge();
+        assert_eq!(grid.bounding_box_of_color([108, 108, 127, 255]), Some(((0, 0, 2), (1, 2, 2))));
+        assert_eq!(grid.bounding_box_of_color([90, 120, 20, 255]), Some(((2, 0, 2), (2, 2, 2))));
+        assert_eq!(grid.bounding_box_of_color([120, 80, 50, 255]), Some(((0, 0, 0), (2, 2, 1))));
+        assert_eq!(grid.bounding_box_of_color(RED), None);
+    }
 
-        let blue = [0, 0, 255, 0];
-        let voxel = Voxel::from_rgba(&blue);
-        for x in 0..grid_width {
-            for y in 0..grid_depth {
-                for z in 0..grid_height {
-                    *grid.get_mut(x, y, z) = voxel;
-                }
+    #[test]
+    fn test_grid_is_uniform() {
+        let mut grid = Grid::new(2, 3, 2);
+        grid.map_in_place(|v| *v = RED_VOXEL);
+        assert_eq!(grid.is_uniform(), Some(&RED_VOXEL));
+        assert!(grid.is_solid());
+        *grid.get_mut(1, 2, 1) = Voxel::from_rgba(&[255, 0, 0, 128]);
+        assert_eq!(grid.is_uniform(), None);
+        assert!(grid.is_solid());
+        *grid.get_mut(1, 2, 1) = Voxel::from_rgba(&[255, 0, 0, 0]);
+        assert!(!grid.is_solid());
+        assert_eq!(gen_test_road_edge().is_uniform(), None);
+        assert_eq!(Grid::<u8>::new(0, 2, 2).is_uniform(), None);
+    }
+
+    #[test]
+    fn test_dither_alpha() {
+        let mut grid = Grid::new(16, 16, 16);
+        grid.map_in_place(|v| *v = RED_VOXEL);
+        let mut other = grid.clone();
+        grid.dither_alpha(7, 0.25);
+        other.dither_alpha(7, 0.25);
+        assert_eq!(grid, other);
+        let kept = grid.count_by(|v| v.as_rgba()[3] > 0)[&true];
+        assert!((900..1150).contains(&kept), "kept {}", kept);
+        other.dither_alpha(8, 1.0);
+        assert_eq!(other, grid);
+        other.dither_alpha(8, 0.0);
+        assert!(other.enumerate_cells().all(|(.., v)| v.as_rgba()[3] == 0));
+    }
+
+    #[test]
+    fn test_grid_map_with_neighbors() {
+        // Each cell takes the value held by most of its six face neighbors,
+        // keeping its own on a tie
+        fn majority<'a>(v: &'a u8, at: &dyn Fn(i32, i32, i32) -> Option<&'a u8>) -> u8 {
+            let faces = [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)];
+            let neighbors: Vec<u8> = faces.iter().filter_map(|(dx, dy, dz)| at(*dx, *dy, *dz).copied()).collect();
+            let ones = neighbors.iter().filter(|n| **n == 1).count();
+            match (2 * ones).cmp(&neighbors.len()) {
+                std::cmp::Ordering::Greater => 1,
+                std::cmp::Ordering::Less => 0,
+                std::cmp::Ordering::Equal => *v,
             }
         }
-        let bytes = vox::encode(&grid).unwrap();
-        fs::write("test_transparent.vox", &bytes).unwrap();
+        let mut grid: Grid<u8> = Grid::new(5, 5, 5);
+        grid.map_region((1, 1, 1), (3, 3, 3), |_, _, _, v| *v = 1);
+        *grid.get_mut(2, 2, 2) = 0;
+        *grid.get_mut(0, 4, 4) = 1;
+        let smoothed = grid.map_with_neighbors(majority);
+        assert_eq!(*smoothed.get(2, 2, 2), 1);
+        assert_eq!(*smoothed.get(0, 4, 4), 0);
+        // Block corners have three of six neighbors set and keep their value
+        assert_eq!(*smoothed.get(1, 1, 1), 1);
+        assert_eq!(*smoothed.get(0, 2, 2), 0);
+        assert_eq!(*grid.get(2, 2, 2), 0);
     }
 
-    fn gen_test_road_edge() -> Grid<Voxel> {
-        let width = 3;
-        let depth = 3;
-        let height = 3;
-        let mut grid = Grid::new(width, depth, height);
-        let grey = Voxel::from_rgba(&[108, 108, 127, 255]);
-        let green = Voxel::from_rgba(&[90, 120, 20, 255]);
-        let brown = Voxel::from_rgba(&[120, 80, 50, 255]);
-        for x in 0..width {
-            for y in 0..depth {
-                for z in 0..height {
-                    if z == height - 1 && x <= width / 2 {
-                        *grid.get_mut(x, y, z) = grey;
-                    } else if z == height - 1 {
-                        *grid.get_mut(x, y, z) = green;
-                    } else {
-                        *grid.get_mut(x, y, z) = brown;
-                    }
-                }
+    #[test]
+    fn test_from_heightmap() {
+        let grid = Grid::from_heightmap(&[0, 1, 3, 2], 2, 2, |x, y, z| Voxel::from_rgba(&[x as u8, y as u8, z as u8, 255]));
+        assert_eq!((grid.width(), grid.depth(), grid.height()), (2, 2, 3));
+        for (x, y, height) in [(0, 0, 0), (1, 0, 1), (0, 1, 3), (1, 1, 2)] {
+            for z in 0..3 {
+                let expected = if z < height { [x as u8, y as u8, z as u8, 255] } else { [0; 4] };
+                assert_eq!(grid.get(x, y, z).as_rgba(), expected);
             }
         }
-        grid
     }
 
     #[test]
-    fn test_voxel_rotated_z_0() {
-        let grid = gen_test_road_edge();
-        let rotated = grid.rotated_z(&Rotation::R0);
-        let bytes = vox::encode(&rotated).unwrap();
-        fs::write("test_road_rotated_z_0.vox", &bytes).unwrap();
+    fn test_outline() {
+        let mut grid = Grid::new(3, 3, 3);
+        *grid.get_mut(1, 1, 1) = RED_VOXEL;
+        let yellow = [255, 255, 0, 255];
+        let outlined = grid.outline(yellow);
+        assert_eq!(*outlined.get(1, 1, 1), RED_VOXEL);
+        let shell = outlined.color_mask(yellow);
+        for (x, y, z, s) in shell.enumerate_cells() {
+            let distance = [x, y, z].iter().map(|c| c.abs_diff(1)).sum::<u32>();
+            assert_eq!(*s, u8::from(distance == 1));
+        }
     }
 
     #[test]
-    fn test_voxel_rotated_z_90() {
+    fn test_grid_tile() {
+        let mut grid: Grid<u32> = Grid::new(2, 2, 2);
+        for (x, y, z, v) in grid.enumerate_cells_mut() {
+            *v = x + 10 * y + 100 * z;
+        }
+        let tiled = grid.tile(2, 1, 1);
+        assert_eq!((tiled.width(), tiled.depth(), tiled.height()), (4, 2, 2));
+        for (x, y, z, v) in grid.enumerate_cells() {
+            assert_eq!(tiled.get(x, y, z), v);
+            assert_eq!(tiled.get(x + 2, y, z), v);
+        }
+        assert_eq!(grid.tile(3, 0, 2).cell_count(), 0);
+    }
+
+    #[test]
+    fn test_grid_find() {
+        let magenta = Voxel::from_rgba(&[255, 0, 255, 255]);
+        let mut grid = gen_test_road_edge();
+        assert_eq!(grid.find(|v| *v == magenta), None);
+        *grid.get_mut(2, 1, 1) = magenta;
+        assert_eq!(grid.find(|v| *v == magenta), Some((2, 1, 1)));
+        assert_eq!(grid.find(|v| v.as_rgba()[3] > 0), Some((0, 0, 0)));
+    }
+
+    #[test]
+    fn test_grid_positions_of() {
         let grid = gen_test_road_edge();
-        let rotated = grid.rotated_z(&Rotation::R90);
-        let bytes = vox::encode(&rotated).unwrap();
-        fs::write("test_road_rotated_z_90.vox", &bytes).unwrap();
+        let grey = Voxel::from_rgba(&[108, 108, 127, 255]);
+        let positions: HashSet<_> = grid.positions_of(|v| *v == grey).into_iter().collect();
+        let expected: HashSet<_> = (0..3).flat_map(|y| [(0, y, 2), (1, y, 2)]).collect();
+        assert_eq!(positions, expected);
+        assert_eq!(grid.positions_of(|v| *v != grey).len(), 21);
+        assert!(grid.positions_of(|v| *v == RED_VOXEL).is_empty());
     }
 
     #[test]
-    fn test_voxel_rotated_z_180() {
+    fn test_from_ascii() {
+        let palette = HashMap::from([('#', [120, 80, 50, 255]), ('r', RED)]);
+        let stairs = Grid::from_ascii(&["###\n###", "r##\n.##", "..#\n. #"], &palette).unwrap();
+        assert_eq!((stairs.width(), stairs.depth(), stairs.height()), (3, 2, 3));
+        assert_eq!(stairs.get(0, 0, 0).as_rgba(), [120, 80, 50, 255]);
+        assert_eq!(*stairs.get(0, 0, 1), RED_VOXEL);
+        assert_eq!(stairs.get(0, 1, 1).as_rgba()[3], 0);
+        assert_eq!(stairs.get(1, 1, 2).as_rgba()[3], 0);
+        assert_eq!(stairs.get(2, 1, 2).as_rgba(), [120, 80, 50, 255]);
+        assert_eq!(stairs.positions_of(|v| v.as_rgba()[3] > 0).len(), 13);
+
+        assert_eq!(Grid::from_ascii(&[], &palette), Err(ParseError::NoLayers));
+        assert_eq!(
+            Grid::from_ascii(&["##\n##", "##"], &palette),
+            Err(ParseError::RowCount { layer: 1, expected: 2, actual: 1 })
+        );
+        assert_eq!(
+            Grid::from_ascii(&["##\n#"], &palette),
+            Err(ParseError::RowLength { layer: 0, row: 1, expected: 2, actual: 1 })
+        );
+        assert_eq!(
+            Grid::from_ascii(&["#x"], &palette),
+            Err(ParseError::UnknownChar { layer: 0, row: 0, column: 1, char: 'x' })
+        );
+    }
+
+    #[test]
+    fn test_adjust() {
         let grid = gen_test_road_edge();
-        let rotated = grid.rotated_z(&Rotation::R180);
-        let bytes = vox::encode(&rotated).unwrap();
-        fs::write("test_road_rotated_z_180.vox", &bytes).unwrap();
+        let mut adjusted = grid.clone();
+        adjusted.adjust(0.0, 1.0);
+        assert_eq!(adjusted, grid);
+        adjusted.adjust(0.1, 1.0);
+        assert_eq!(adjusted.get(0, 0, 2).as_rgba(), [134, 134, 153, 255]);
+        assert_eq!(adjusted.get(0, 0, 0).as_rgba(), [146, 106, 76, 255]);
+        adjusted.adjust(1.0, 1.0);
+        assert_eq!(adjusted.get(0, 0, 0).as_rgba(), [255, 255, 255, 255]);
+
+        let mut contrast = grid.clone();
+        *contrast.get_mut(1, 1, 1) = Voxel::from_rgba(&[200, 10, 10, 0]);
+        contrast.adjust(0.0, 2.0);
+        assert_eq!(contrast.get(0, 0, 0).as_rgba(), [113, 33, 0, 255]);
+        assert_eq!(contrast.get(1, 1, 1).as_rgba(), [200, 10, 10, 0]);
     }
 
     #[test]
-    fn test_voxel_rotated_z_270() {
+    fn test_diff_color() {
+        let (green, red, blue) = ([0, 255, 0, 255], RED, [0, 0, 255, 255]);
+        let before = gen_test_road_edge().with_border(1, Voxel::from_rgba(&[0; 4]));
+        let mut after = before.clone();
+        *after.get_mut(2, 2, 4) = RED_VOXEL;
+        *after.get_mut(1, 1, 1) = Voxel::from_rgba(&[120, 80, 50, 0]);
+        let diff = before.diff_color(&after, green, red, blue);
+        let marked = diff.positions_of(|v| v.as_rgba()[3] > 0);
+        assert_eq!(marked, [(1, 1, 1), (2, 2, 4)]);
+        assert_eq!(diff.get(2, 2, 4).as_rgba(), green);
+        assert_eq!(diff.get(1, 1, 1).as_rgba(), red);
+        *after.get_mut(3, 3, 3) = RED_VOXEL;
+        assert_eq!(before.diff_color(&after, green, red, blue).get(3, 3, 3).as_rgba(), blue);
+    }
+
+    #[test]
+    fn test_exposed_face_counts() {
+        let mut floor = Grid::new(5, 5, 3);
+        floor.map_region((1, 1, 1), (3, 2, 1), |_, _, _, v| *v = RED_VOXEL);
+        assert_eq!(floor.exposed_face_counts(), [2, 2, 3, 3, 6, 6]);
         let grid = gen_test_road_edge();
-        let rotated = grid.rotated_z(&Rotation::R270);
-        let bytes = vox::encode(&rotated).unwrap();
-        fs::write("test_road_rotated_z_270.vox", &bytes).unwrap();
+        let counts = grid.exposed_face_counts();
+        assert_eq!(counts, [9; 6]);
+        assert_eq!(counts.iter().sum::<usize>() * 4, mesh::Mesh::from_grid(&grid).positions.len());
+    }
+
+    #[test]
+    fn test_symmetrize_x() {
+        let mut asymmetric = gen_test_road_edge().with_border(1, Voxel::from_rgba(&[0; 4]));
+        *asymmetric.get_mut(0, 2, 2) = RED_VOXEL;
+        *asymmetric.get_mut(3, 1, 4) = RED_VOXEL;
+        for prefer in [SymPref::KeepLow, SymPref::KeepHigh, SymPref::Union] {
+            let mut grid = asymmetric.clone();
+            grid.symmetrize_x(prefer);
+            assert_eq!(grid, grid.mirror(Axis::X));
+            let (low, high) = (*grid.get(0, 2, 2), *grid.get(1, 1, 4));
+            match prefer {
+                SymPref::KeepLow => assert_eq!((low.as_rgba()[3], high.as_rgba()[3]), (255, 0)),
+                SymPref::KeepHigh => assert_eq!((low.as_rgba()[3], high.as_rgba()[3]), (0, 255)),
+                SymPref::Union => assert_eq!((low, high), (RED_VOXEL, RED_VOXEL)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_flood_fill_tolerant() {
+        // Red rises by 10 per step along x, with an unreachable close color
+        // past a wall
+        let mut grid = Grid::new(8, 1, 1);
+        for (x, _, _, v) in grid.enumerate_cells_mut() {
+            *v = Voxel::from_rgba(&[100 + 10 * x as u8, 0, 0, 255]);
+        }
+        *grid.get_mut(5, 0, 0) = Voxel::from_rgba(&[0, 0, 255, 255]);
+        *grid.get_mut(6, 0, 0) = Voxel::from_rgba(&[100, 0, 0, 255]);
+        let before = grid.clone();
+        grid.flood_fill_tolerant(1, 0, 0, RED_VOXEL, 25);
+        let filled = grid.positions_of(|v| *v == RED_VOXEL);
+        assert_eq!(filled, [(0, 0, 0), (1, 0, 0), (2, 0, 0), (3, 0, 0)]);
+        for x in 4..8 {
+            assert_eq!(grid.get(x, 0, 0), before.get(x, 0, 0));
+        }
+    }
+
+    #[test]
+    fn test_grid_fill_checker() {
+        for (x, y, z) in [(0, 0, 0), (1, 0, 0), (1, 1, 0), (2, 3, 4), (u32::MAX, 1, 0), (7, 7, 7)] {
+            assert_eq!(parity(x, y, z), (x as u64 + y as u64 + z as u64) % 2 == 1);
+        }
+        let mut grid: Grid<u8> = Grid::new(3, 4, 5);
+        grid.fill_checker(10, 20);
+        for (x, y, z, v) in grid.enumerate_cells() {
+            assert_eq!(*v, if (x + y + z) % 2 == 0 { 10 } else { 20 });
+        }
+    }
+
+    #[test]
+    fn test_grid_find_path() {
+        // A wall across y = 2 with a gap at x = 4
+        let mut grid = Grid::new(5, 5, 1);
+        grid.map_region((0, 2, 0), (3, 2, 0), |_, _, _, v| *v = RED_VOXEL);
+        let empty = |v: &Voxel| v.as_rgba()[3] == 0;
+        let path = grid.find_path((0, 0, 0), (0, 4, 0), empty).unwrap();
+        assert_eq!(path.len(), 13);
+        assert_eq!((path[0], path[12]), ((0, 0, 0), (0, 4, 0)));
+        assert!(path.contains(&(4, 2, 0)));
+        for pair in path.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert_eq!(a.0.abs_diff(b.0) + a.1.abs_diff(b.1) + a.2.abs_diff(b.2), 1);
+            assert!(empty(grid.get(b.0, b.1, b.2)));
+        }
+        assert_eq!(grid.find_path((1, 1, 0), (1, 1, 0), empty), Some(vec![(1, 1, 0)]));
+        *grid.get_mut(4, 2, 0) = RED_VOXEL;
+        assert_eq!(grid.find_path((0, 0, 0), (0, 4, 0), empty), None);
+        assert_eq!(grid.find_path((0, 0, 0), (0, 2, 0), empty), None);
+    }
+
+    #[test]
+    fn test_flood_recolor() {
+        let mut grid = Grid::new(4, 1, 2);
+        for (x, _, z, v) in grid.enumerate_cells_mut() {
+            *v = Voxel::from_rgba(&[100, 100, 100, 40 + 60 * x as u8 + z as u8]);
+        }
+        *grid.get_mut(3, 0, 1) = Voxel::from_rgba(&[0, 0, 0, 255]);
+        grid.get_mut(1, 0, 0).set_emissive(true);
+        let before = grid.clone();
+        grid.flood_recolor(0, 0, 0, [0, 200, 50], 0);
+        for (x, y, z, v) in grid.enumerate_cells() {
+            let original = before.get(x, y, z);
+            assert_eq!(v.as_rgba()[3], original.as_rgba()[3]);
+            assert_eq!(v.is_emissive(), original.is_emissive());
+            let expected = if (x, z) == (3, 1) { [0, 0, 0] } else { [0, 200, 50] };
+            assert_eq!(v.as_rgba()[..3], expected);
+        }
+    }
+
+    #[test]
+    fn test_to_point_cloud() {
+        let mut grid = Grid::new(4, 3, 2);
+        let blue = Voxel::from_rgba(&[0, 0, 255, 128]);
+        *grid.get_mut(0, 0, 0) = RED_VOXEL;
+        *grid.get_mut(3, 1, 1) = blue;
+        assert_eq!(grid.to_point_cloud(), [([0.5, 0.5, 0.5], RED), ([3.5, 1.5, 1.5], [0, 0, 255, 128])]);
+        assert_eq!(grid.to_point_cloud_scaled(0.1)[1].0, [3.5 * 0.1, 1.5 * 0.1, 1.5 * 0.1]);
+    }
+
+    #[test]
+    fn test_stamp() {
+        // L along +x with its foot along +y
+        let mut prop = Grid::new(3, 2, 1);
+        for x in 0..3 {
+            *prop.get_mut(x, 0, 0) = RED_VOXEL;
+        }
+        *prop.get_mut(0, 1, 0) = RED_VOXEL;
+        let blue = Voxel::from_rgba(&[0, 0, 255, 255]);
+        let mut grid = Grid::new(6, 6, 2);
+        grid.map_region((0, 0, 0), (5, 5, 0), |_, _, _, v| *v = blue);
+        grid.stamp(&prop, (1, 1, 0), Orientation::about(Axis::Z, &Rotation::R90), false);
+        // A quarter turn runs the L along +y with the foot toward -x
+        let stamped: HashSet<_> = grid.positions_of(|v| *v == RED_VOXEL).into_iter().collect();
+        assert_eq!(stamped, HashSet::from([(2, 1, 0), (2, 2, 0), (2, 3, 0), (1, 1, 0)]));
+        assert_eq!(*grid.get(1, 2, 0), blue);
+
+        let half_red = Voxel::from_rgba(&[255, 0, 0, 128]);
+        let mut prop = Grid::new(1, 1, 2);
+        prop.map_in_place(|v| *v = half_red);
+        grid.stamp(&prop, (5, 5, 0), Orientation::IDENTITY, true);
+        assert_eq!(*grid.get(5, 5, 0), half_red.blend_over(&blue));
+        assert_eq!(*grid.get(5, 5, 1), half_red);
+        grid.stamp(&prop, (5, 5, 1), Orientation::IDENTITY, false);
+        assert_eq!(*grid.get(5, 5, 1), half_red);
+    }
+
+    #[test]
+    fn test_grid_equalize() {
+        let mut field: Grid<u8> = Grid::new(4, 4, 4);
+        for (x, y, z, v) in field.enumerate_cells_mut() {
+            *v = 100 + ((x + y + z) % 4) as u8 * 3;
+        }
+        let before = field.clone();
+        field.equalize();
+        assert_eq!(field.enumerate_cells().map(|(.., v)| *v).min(), Some(0));
+        assert_eq!(field.enumerate_cells().map(|(.., v)| *v).max(), Some(255));
+        assert_eq!(field.distinct_values(), 4);
+        // Order between cells is kept
+        for ((.., a), (.., b)) in before.enumerate_cells().zip(field.enumerate_cells()) {
+            for ((.., c), (.., d)) in before.enumerate_cells().zip(field.enumerate_cells()) {
+                assert_eq!(a.cmp(c), b.cmp(d));
+            }
+        }
+        let mut uniform: Grid<u8> = Grid::new(2, 2, 2);
+        uniform.map_in_place(|v| *v = 42);
+        uniform.equalize();
+        assert_eq!(uniform.is_uniform(), Some(&42));
     }
 
     #[test]
@@ -0,0 +1,161 @@
+use crate::{srgb_to_linear, Grid, Voxel};
+use std::io::Write;
+
+// Axis of each face normal with the two in-plane axes ordered so that
+// u x v points along the normal
+const FACE_AXES: [(usize, usize, usize); 3] = [(0, 1, 2), (1, 2, 0), (2, 0, 1)];
+
+// Triangle mesh of the voxel faces that border empty space, one quad of four
+// vertices per face. Positions are in grid units with z up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub colors: Vec<[u8; 4]>,
+    // Per vertex color of emissive voxels, zero for all others
+    pub emissive: Vec<[u8; 4]>,
+    pub indices: Vec<u32>,
+}
+
+impl Mesh {
+    pub fn from_grid(grid: &Grid<Voxel>) -> Mesh {
+        let mut mesh = Mesh::default();
+        let opaque = |x: i64, y: i64, z: i64| {
+            x >= 0
+                && y >= 0
+                && z >= 0
+                && grid.contains(x as u32, y as u32, z as u32)
+                && grid.get(x as u32, y as u32, z as u32).as_rgba()[3] > 0
+        };
+        for (x, y, z, v) in grid.enumerate_cells() {
+            let rgba = v.as_rgba();
+            if rgba[3] == 0 {
+                continue;
+            }
+            let color: [u8; 4] = rgba.try_into().unwrap();
+            let emissive = if v.is_emissive() { color } else { [0; 4] };
+            let p = [x as i64, y as i64, z as i64];
+            for (a, u, w) in FACE_AXES {
+                for sign in [1, -1] {
+                    let mut n = p;
+                    n[a] += sign;
+                    if opaque(n[0], n[1], n[2]) {
+                        continue;
+                    }
+                    let corners = if sign > 0 {
+                        [(0, 0), (1, 0), (1, 1), (0, 1)]
+                    } else {
+                        [(0, 0), (0, 1), (1, 1), (1, 0)]
+                    };
+                    let base = mesh.positions.len() as u32;
+                    for (cu, cw) in corners {
+                        let mut position = p.map(|c| c as f32);
+                        position[a] += if sign > 0 { 1.0 } else { 0.0 };
+                        position[u] += cu as f32;
+                        position[w] += cw as f32;
+                        let mut normal = [0.0; 3];
+                        normal[a] = sign as f32;
+                        mesh.positions.push(position);
+                        mesh.normals.push(normal);
+                        mesh.colors.push(color);
+                        mesh.emissive.push(emissive);
+                    }
+                    mesh.indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+                }
+            }
+        }
+        mesh
+    }
+}
+
+// Writes a glTF 2.0 JSON document with the y-up mesh and linear vertex
+// colors in one buffer embedded as a base64 data URI. A grid with no exposed
+// faces gives an empty scene, since glTF forbids zero length buffers.
+pub fn write_gltf(grid: &Grid<Voxel>, w: &mut impl Write) -> std::io::Result<()> {
+    let mesh = Mesh::from_grid(&grid.to_y_up());
+    let count = mesh.positions.len();
+    if count == 0 {
+        return write!(
+            w,
+            r#"{{"asset":{{"version":"2.0","generator":"sol-grid"}},"scene":0,"scenes":[{{}}]}}"#
+        );
+    }
+    let (mut min, mut max) = (mesh.positions[0], mesh.positions[0]);
+    let mut buffer = Vec::with_capacity(count * 40 + mesh.indices.len() * 4);
+    for p in &mesh.positions {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+        buffer.extend(p.iter().flat_map(|c| c.to_le_bytes()));
+    }
+    for n in &mesh.normals {
+        buffer.extend(n.iter().flat_map(|c| c.to_le_bytes()));
+    }
+    // glTF vertex colors are linear, while voxel colors are sRGB
+    for c in &mesh.colors {
+        let linear = [srgb_to_linear(c[0]), srgb_to_linear(c[1]), srgb_to_linear(c[2])];
+        let rgba = [linear[0], linear[1], linear[2], c[3] as f32 / 255.0];
+        buffer.extend(rgba.iter().flat_map(|c| c.to_le_bytes()));
+    }
+    for i in &mesh.indices {
+        buffer.extend(i.to_le_bytes());
+    }
+    // Byte offsets of the normals, colors and indices views
+    let offsets = [count * 12, count * 24, count * 40];
+    write!(
+        w,
+        concat!(
+            r#"{{"asset":{{"version":"2.0","generator":"sol-grid"}},"#,
+            r#""scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"#,
+            r#""meshes":[{{"primitives":[{{"#,
+            r#""attributes":{{"POSITION":0,"NORMAL":1,"COLOR_0":2}},"indices":3}}]}}],"#,
+            r#""buffers":[{{"byteLength":{},"uri":"data:application/octet-stream;base64,{}"}}],"#,
+            r#""bufferViews":["#,
+            r#"{{"buffer":0,"byteOffset":0,"byteLength":{},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":34962}},"#,
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":34963}}],"#,
+            r#""accessors":["#,
+            r#"{{"bufferView":0,"componentType":5126,"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}},"#,
+            r#"{{"bufferView":1,"componentType":5126,"count":{},"type":"VEC3"}},"#,
+            r#"{{"bufferView":2,"componentType":5126,"count":{},"type":"VEC4"}},"#,
+            r#"{{"bufferView":3,"componentType":5125,"count":{},"type":"SCALAR"}}]}}"#,
+        ),
+        buffer.len(),
+        base64(&buffer),
+        offsets[0],
+        offsets[0],
+        offsets[1] - offsets[0],
+        offsets[1],
+        offsets[2] - offsets[1],
+        offsets[2],
+        buffer.len() - offsets[2],
+        count,
+        min[0],
+        min[1],
+        min[2],
+        max[0],
+        max[1],
+        max[2],
+        count,
+        count,
+        mesh.indices.len(),
+    )
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
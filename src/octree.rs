@@ -0,0 +1,115 @@
+use crate::{Grid, Voxel};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Node {
+    Leaf(Voxel),
+    // Children indexed by x in bit 0, y in bit 1 and z in bit 2
+    Branch(Box<[Node; 8]>),
+}
+
+// Sparse grid over the smallest power of two cube holding the source grid.
+// Regions of a single voxel collapse into leaves, cells outside the source
+// grid count as empty.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Octree {
+    width: u32,
+    depth: u32,
+    height: u32,
+    size: u32,
+    root: Node,
+}
+
+impl Octree {
+    pub fn from_grid(grid: &Grid<Voxel>) -> Octree {
+        let size = grid.width().max(grid.depth()).max(grid.height()).max(1).next_power_of_two();
+        Octree {
+            width: grid.width(),
+            depth: grid.depth(),
+            height: grid.height(),
+            size,
+            root: Self::build(grid, (0, 0, 0), size),
+        }
+    }
+
+    fn build(grid: &Grid<Voxel>, min: (u32, u32, u32), size: u32) -> Node {
+        // Regions wholly outside the grid are empty, so flat or thin grids
+        // never descend through the rest of the cube
+        if min.0 >= grid.width() || min.1 >= grid.depth() || min.2 >= grid.height() {
+            return Node::Leaf(Voxel([0; 8]));
+        }
+        if let Some(v) = Self::uniform(grid, min, size) {
+            return Node::Leaf(v);
+        }
+        let half = size / 2;
+        let children: [Node; 8] = std::array::from_fn(|i| {
+            let i = i as u32;
+            let child = (min.0 + (i & 1) * half, min.1 + (i >> 1 & 1) * half, min.2 + (i >> 2) * half);
+            Self::build(grid, child, half)
+        });
+        match &children[0] {
+            Node::Leaf(v) if children.iter().all(|c| *c == Node::Leaf(*v)) => Node::Leaf(*v),
+            _ => Node::Branch(Box::new(children)),
+        }
+    }
+
+    // The voxel filling the region, counting cells outside the grid as empty
+    fn uniform(grid: &Grid<Voxel>, min: (u32, u32, u32), size: u32) -> Option<Voxel> {
+        let max = (
+            (min.0 + size).min(grid.width()),
+            (min.1 + size).min(grid.depth()),
+            (min.2 + size).min(grid.height()),
+        );
+        let clipped = max != (min.0 + size, min.1 + size, min.2 + size);
+        let first = if clipped { Voxel([0; 8]) } else { *grid.get(min.0, min.1, min.2) };
+        for z in min.2..max.2 {
+            for y in min.1..max.1 {
+                for x in min.0..max.0 {
+                    if *grid.get(x, y, z) != first {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(first)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn get(&self, x: u32, y: u32, z: u32) -> &Voxel {
+        if x >= self.width || y >= self.depth || z >= self.height {
+            panic!(
+                "Octree index {:?} out of bounds {:?}",
+                (x, y, z),
+                (self.width, self.depth, self.height)
+            );
+        }
+        let mut node = &self.root;
+        let mut half = self.size / 2;
+        loop {
+            match node {
+                Node::Leaf(v) => return v,
+                Node::Branch(children) => {
+                    let i = (x & half != 0) as usize
+                        | ((y & half != 0) as usize) << 1
+                        | ((z & half != 0) as usize) << 2;
+                    node = &children[i];
+                    half /= 2;
+                },
+            }
+        }
+    }
+}
@@ -1,79 +1,1044 @@
-use crate::{Grid, Voxel};
-use std::collections::HashMap;
+use crate::{Codec, Grid, Voxel};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-use std::io::Write;
+use std::io::{Read, Write};
+use std::path::Path;
 
 pub fn encode(grid: &Grid<Voxel>) -> std::io::Result<Vec<u8>> {
-    // Calculate vox data
-    let mut color_indices = HashMap::new();
-    let mut index = 1;
-    let mut xyzis = Vec::new();
-    for (x, y, z, v) in grid.enumerate_cells() {
-        let mut xyzi = [0; 4];
-        xyzi[0] = x as u8;
-        xyzi[1] = y as u8;
-        xyzi[2] = z as u8;
+    // First pass builds the palette and counts the voxels so the XYZI
+    // entries can be streamed by the second
+    let mut palette = Palette::new();
+    let mut voxel_count: usize = 0;
+    for (_, _, _, v) in grid.enumerate_cells() {
         let rgba = v.as_rgba();
-        match color_indices.get(rgba) {
-            None => {
-                color_indices.insert(rgba, index);
-                xyzi[3] = index;
-                index += 1;
-            },
-            Some(i) => {
-                xyzi[3] = *i as u8;
-            },
-        }
+        palette_index(&mut palette, rgba.try_into().unwrap())?;
         if rgba[3] > 0 {
-            xyzis.push(xyzi);
+            voxel_count += 1;
         }
     }
     // Vox spec: https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt
     let mut bytes = Vec::new();
-    bytes.write(b"VOX ")?;
-    bytes.write(&u32::to_le_bytes(150))?;
+    bytes.write_all(b"VOX ")?;
+    bytes.write_all(&u32::to_le_bytes(150))?;
 
-    const INT_SIZE: u32 = 4;
     const ZERO: [u8; 4] = [0; 4];
-    let size_chunk_size = INT_SIZE * 3;
-    // TODO: Handle cases where voxel count exeeds u32 bounds
-    let voxel_count = xyzis.len() as u32;
-    let xyzi_chunk_size = INT_SIZE + (voxel_count * INT_SIZE);
-    const PALETTE_COUNT: u32 = 256;
-    let rgba_chunk_size = PALETTE_COUNT * INT_SIZE;
-    let chunk_header_size = INT_SIZE * 3;
-    let chunk_count = 3;
-    let main_child_chunks_size = (chunk_header_size * chunk_count)
-        + size_chunk_size
-        + xyzi_chunk_size
-        + rgba_chunk_size;
-    bytes.write(b"MAIN")?;
-    bytes.write(&ZERO)?; // MAIN has no content
-    bytes.write(&u32::to_le_bytes(main_child_chunks_size))?;
-
-    bytes.write(b"SIZE")?;
-    bytes.write(&u32::to_le_bytes(size_chunk_size))?;
-    bytes.write(&ZERO)?; // SIZE has no children
-    bytes.write(&u32::to_le_bytes(grid.width()))?;
-    bytes.write(&u32::to_le_bytes(grid.depth()))?;
-    bytes.write(&u32::to_le_bytes(grid.height()))?;
-
-    bytes.write(b"XYZI")?;
-    bytes.write(&u32::to_le_bytes(xyzi_chunk_size))?;
-    bytes.write(&ZERO)?; // XYZI has no children
-    bytes.write(&u32::to_le_bytes(voxel_count))?;
+    let (voxel_count, xyzi_chunk_size, main_child_chunks_size) = chunk_sizes(voxel_count)?;
+    bytes.write_all(b"MAIN")?;
+    bytes.write_all(&ZERO)?; // MAIN has no content
+    bytes.write_all(&u32::to_le_bytes(main_child_chunks_size))?;
+
+    bytes.write_all(b"SIZE")?;
+    bytes.write_all(&u32::to_le_bytes(SIZE_CHUNK_SIZE))?;
+    bytes.write_all(&ZERO)?; // SIZE has no children
+    bytes.write_all(&u32::to_le_bytes(grid.width()))?;
+    bytes.write_all(&u32::to_le_bytes(grid.depth()))?;
+    bytes.write_all(&u32::to_le_bytes(grid.height()))?;
+
+    bytes.write_all(b"XYZI")?;
+    bytes.write_all(&u32::to_le_bytes(xyzi_chunk_size))?;
+    bytes.write_all(&ZERO)?; // XYZI has no children
+    bytes.write_all(&u32::to_le_bytes(voxel_count))?;
     // TODO: Handle cases where xyzi exceeds u8 bounds
-    for xyzi in &xyzis {
-        bytes.write(xyzi)?;
+    for (x, y, z, v) in grid.enumerate_cells() {
+        let rgba = v.as_rgba();
+        if rgba[3] > 0 {
+            let index = palette.index_of(rgba.try_into().unwrap()).unwrap() + 1;
+            bytes.write_all(&[x as u8, y as u8, z as u8, index])?;
+        }
+    }
+
+    bytes.write_all(b"RGBA")?;
+    bytes.write_all(&u32::to_le_bytes(RGBA_CHUNK_SIZE))?;
+    bytes.write_all(&ZERO)?; // RGBA has no children
+    bytes.write_all(&palette.colors.concat())?;
+    Ok(bytes)
+}
+
+const INT_SIZE: u32 = 4;
+const SIZE_CHUNK_SIZE: u32 = INT_SIZE * 3;
+const RGBA_CHUNK_SIZE: u32 = 256 * INT_SIZE;
+
+// The voxel count, XYZI content size and MAIN children size of a single model
+// file, failing when any of them exceeds u32
+pub(crate) fn chunk_sizes(voxel_count: usize) -> Result<(u32, u32, u32), VoxError> {
+    let chunk_header_size = INT_SIZE * 3;
+    let sizes = u32::try_from(voxel_count).ok().and_then(|count| {
+        let xyzi_chunk_size = count.checked_mul(INT_SIZE)?.checked_add(INT_SIZE)?;
+        let main_child_chunks_size = (chunk_header_size * 3 + SIZE_CHUNK_SIZE + RGBA_CHUNK_SIZE)
+            .checked_add(xyzi_chunk_size)?;
+        Some((count, xyzi_chunk_size, main_child_chunks_size))
+    });
+    sizes.ok_or(VoxError::SizeOverflow)
+}
+
+// A little endian size or count field, failing when it exceeds u32
+pub(crate) fn size_field(size: usize) -> Result<[u8; 4], VoxError> {
+    u32::try_from(size).map(u32::to_le_bytes).map_err(|_| VoxError::SizeOverflow)
+}
+
+// The 256 entry color table of an RGBA chunk, filled in insertion order
+#[derive(Clone, Debug)]
+pub struct Palette {
+    colors: [[u8; 4]; 256],
+    indices: HashMap<[u8; 4], u8>,
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette {
+            colors: [[0; 4]; 256],
+            indices: HashMap::new(),
+        }
     }
 
-    bytes.write(b"RGBA")?;
-    bytes.write(&u32::to_le_bytes(rgba_chunk_size))?;
-    bytes.write(&ZERO)?; // RGBA has no children
-    let mut palette = [[0; 4]; PALETTE_COUNT as usize];
-    for (rgba, i) in color_indices {
-        palette[i as usize - 1] = rgba.try_into().unwrap();
+    pub fn len(&self) -> usize {
+        self.indices.len()
     }
-    bytes.write(&palette.concat())?;
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    // Index of the color, adding it to the next free entry if it is new.
+    // None when the palette is full.
+    pub fn insert(&mut self, color: [u8; 4]) -> Option<u8> {
+        if let Some(index) = self.index_of(color) {
+            return Some(index);
+        }
+        let index = u8::try_from(self.len()).ok()?;
+        self.colors[index as usize] = color;
+        self.indices.insert(color, index);
+        Some(index)
+    }
+
+    pub fn index_of(&self, color: [u8; 4]) -> Option<u8> {
+        self.indices.get(&color).copied()
+    }
+
+    pub fn color(&self, index: u8) -> [u8; 4] {
+        self.colors[index as usize]
+    }
+
+    // Index of the closest filled entry by Euclidean distance over RGB, with
+    // alpha as a fourth channel so opaque colors prefer opaque entries. 0 when
+    // the palette is empty.
+    pub fn nearest(&self, color: [u8; 4]) -> u8 {
+        let distance = |entry: &[u8; 4]| -> u32 {
+            entry
+                .iter()
+                .zip(color)
+                .map(|(a, b)| (*a as i32 - b as i32).pow(2) as u32)
+                .sum()
+        };
+        (0..self.len())
+            .min_by_key(|i| distance(&self.colors[*i]))
+            .unwrap_or(0) as u8
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new()
+    }
+}
+
+// XYZI color index of the color, which is one past its palette entry since
+// index 0 means empty
+fn palette_index(palette: &mut Palette, rgba: [u8; 4]) -> std::io::Result<u8> {
+    match palette.insert(rgba) {
+        Some(index) if index < 255 => Ok(index + 1),
+        _ => Err(VoxError::PaletteFull.into()),
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EncodeReport {
+    // Voxels written to XYZI
+    pub voxel_count: usize,
+    // Palette entries used, including colors only found on transparent cells
+    pub distinct_colors: usize,
+    // Voxels written per XYZI color index
+    pub index_counts: [usize; 256],
+    // Transparent cells left out of XYZI
+    pub skipped_transparent: usize,
+}
+
+// Statistics about what encode writes for the grid
+pub fn encode_report(grid: &Grid<Voxel>) -> std::io::Result<EncodeReport> {
+    let bytes = encode(grid)?;
+    let mut report = EncodeReport {
+        voxel_count: 0,
+        distinct_colors: 0,
+        index_counts: [0; 256],
+        skipped_transparent: 0,
+    };
+    for (id, content) in main_children(&bytes) {
+        if id == b"XYZI" {
+            for xyzi in content[4..].chunks_exact(4) {
+                report.voxel_count += 1;
+                report.index_counts[xyzi[3] as usize] += 1;
+            }
+        }
+    }
+    let mut palette = Palette::new();
+    for (_, _, _, v) in grid.enumerate_cells() {
+        palette.insert(v.as_rgba().try_into().unwrap());
+    }
+    report.distinct_colors = palette.len();
+    report.skipped_transparent = grid.cell_count() - report.voxel_count;
+    Ok(report)
+}
+
+// A model and the world position of its min corner
+pub type PlacedModel<'a> = (&'a Grid<Voxel>, [i32; 3]);
+
+// Encodes the grid followed by an rOBJ chunk holding the metadata pairs
+pub fn encode_with_metadata(
+    grid: &Grid<Voxel>,
+    metadata: &HashMap<String, String>,
+) -> std::io::Result<Vec<u8>> {
+    let mut bytes = encode(grid)?;
+    let mut pairs: Vec<(&str, &str)> = metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    pairs.sort();
+    pairs.insert(0, ("_type", METADATA_TYPE));
+    append_robj(&mut bytes, &pairs)?;
     Ok(bytes)
-}
\ No newline at end of file
+}
+
+const METADATA_TYPE: &str = "_sol_grid_metadata";
+
+// The subset of MagicaVoxel's render settings written as rOBJ chunks
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderSettings {
+    pub ground_color: [u8; 3],
+    pub sky_color: [u8; 3],
+    pub bloom_mix: f32,
+    pub bloom_scale: f32,
+    pub bloom_threshold: f32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            ground_color: [80, 80, 80],
+            sky_color: [216, 216, 216],
+            bloom_mix: 0.5,
+            bloom_scale: 0.0,
+            bloom_threshold: 1.0,
+        }
+    }
+}
+
+// Encodes the grid followed by _ground, _sky and _bloom rOBJ chunks
+pub fn encode_with_render_settings(
+    grid: &Grid<Voxel>,
+    settings: &RenderSettings,
+) -> std::io::Result<Vec<u8>> {
+    let mut bytes = encode(grid)?;
+    let rgb = |c: [u8; 3]| format!("{} {} {}", c[0], c[1], c[2]);
+    let (ground, sky) = (rgb(settings.ground_color), rgb(settings.sky_color));
+    let bloom = [settings.bloom_mix, settings.bloom_scale, settings.bloom_threshold]
+        .map(|v| v.to_string());
+    append_robj(&mut bytes, &[("_type", "_ground"), ("_color", &ground)])?;
+    append_robj(&mut bytes, &[("_type", "_sky"), ("_color", &sky)])?;
+    let bloom_pairs =
+        [("_type", "_bloom"), ("_mix", &bloom[0]), ("_scale", &bloom[1]), ("_threshold", &bloom[2])];
+    append_robj(&mut bytes, &bloom_pairs)?;
+    Ok(bytes)
+}
+
+// Appends an rOBJ chunk to an encoded file, growing the MAIN children size
+fn append_robj(bytes: &mut Vec<u8>, pairs: &[(&str, &str)]) -> std::io::Result<()> {
+    let mut dict = Vec::new();
+    write_dict(&mut dict, pairs)?;
+    write_chunk(bytes, b"rOBJ", &dict)?;
+    let children_size = bytes.len().checked_sub(20).ok_or(VoxError::MissingMain)?;
+    bytes[16..20].copy_from_slice(&size_field(children_size)?);
+    Ok(())
+}
+
+// Encodes several models into one scene, each group of models assigned to a
+// named layer
+pub fn encode_models_layered(groups: &[(&str, Vec<PlacedModel>)]) -> std::io::Result<Vec<u8>> {
+    let layers: Vec<&str> = groups.iter().map(|(name, _)| *name).collect();
+    let mut models = Vec::new();
+    for (layer, (_, group)) in groups.iter().enumerate() {
+        for (grid, offset) in group {
+            models.push(SceneModel { layer, grid, offset: *offset, name: None, shape_name: None });
+        }
+    }
+    encode_scene(&layers, &models)
+}
+
+// A model placed in a scene, with the _name attributes of its nTRN and nSHP
+// nodes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Model {
+    pub grid: Grid<Voxel>,
+    pub offset: [i32; 3],
+    pub name: Option<String>,
+    pub shape_name: Option<String>,
+}
+
+// Encodes the models into one scene on a single unnamed layer
+pub fn encode_models(models: &[Model]) -> std::io::Result<Vec<u8>> {
+    let models: Vec<SceneModel> = models
+        .iter()
+        .map(|model| SceneModel {
+            layer: 0,
+            grid: &model.grid,
+            offset: model.offset,
+            name: model.name.as_deref(),
+            shape_name: model.shape_name.as_deref(),
+        })
+        .collect();
+    encode_scene(&[""], &models)
+}
+
+struct SceneModel<'a> {
+    layer: usize,
+    grid: &'a Grid<Voxel>,
+    offset: [i32; 3],
+    name: Option<&'a str>,
+    shape_name: Option<&'a str>,
+}
+
+fn encode_scene(layers: &[&str], models: &[SceneModel]) -> std::io::Result<Vec<u8>> {
+    let mut palette = Palette::new();
+    let mut children = Vec::new();
+    for model in models {
+        let grid = model.grid;
+        let mut xyzis = Vec::new();
+        for (x, y, z, v) in grid.enumerate_cells() {
+            let rgba: [u8; 4] = v.as_rgba().try_into().unwrap();
+            let i = palette_index(&mut palette, rgba)?;
+            if rgba[3] > 0 {
+                xyzis.push([x as u8, y as u8, z as u8, i]);
+            }
+        }
+        let mut size = Vec::new();
+        size.write_all(&u32::to_le_bytes(grid.width()))?;
+        size.write_all(&u32::to_le_bytes(grid.depth()))?;
+        size.write_all(&u32::to_le_bytes(grid.height()))?;
+        write_chunk(&mut children, b"SIZE", &size)?;
+        let mut xyzi = Vec::new();
+        xyzi.write_all(&size_field(xyzis.len())?)?;
+        xyzi.write_all(&xyzis.concat())?;
+        write_chunk(&mut children, b"XYZI", &xyzi)?;
+    }
+
+    // Scene graph: root nTRN -> nGRP -> (nTRN -> nSHP) per model. Version
+    // 200 marks the file as placing its models through it.
+    let mut root = Vec::new();
+    root.write_all(&i32::to_le_bytes(0))?;
+    write_dict(&mut root, &[])?;
+    root.write_all(&i32::to_le_bytes(1))?;
+    root.write_all(&i32::to_le_bytes(-1))?;
+    root.write_all(&i32::to_le_bytes(-1))?;
+    root.write_all(&i32::to_le_bytes(1))?;
+    write_dict(&mut root, &[])?;
+    write_chunk(&mut children, b"nTRN", &root)?;
+    let mut group = Vec::new();
+    group.write_all(&i32::to_le_bytes(1))?;
+    write_dict(&mut group, &[])?;
+    group.write_all(&size_field(models.len())?)?;
+    for i in 0..models.len() {
+        group.write_all(&i32::to_le_bytes(2 + 2 * i as i32))?;
+    }
+    write_chunk(&mut children, b"nGRP", &group)?;
+    for (i, model) in models.iter().enumerate() {
+        let grid = model.grid;
+        // MagicaVoxel translations are relative to the model center
+        let translation = format!(
+            "{} {} {}",
+            model.offset[0] + (grid.width() / 2) as i32,
+            model.offset[1] + (grid.depth() / 2) as i32,
+            model.offset[2] + (grid.height() / 2) as i32,
+        );
+        let transform_id = 2 + 2 * i as i32;
+        let mut transform = Vec::new();
+        transform.write_all(&i32::to_le_bytes(transform_id))?;
+        write_dict(&mut transform, &name_pairs(model.name))?;
+        transform.write_all(&i32::to_le_bytes(transform_id + 1))?;
+        transform.write_all(&i32::to_le_bytes(-1))?;
+        transform.write_all(&i32::to_le_bytes(model.layer as i32))?;
+        transform.write_all(&i32::to_le_bytes(1))?;
+        write_dict(&mut transform, &[("_t", &translation)])?;
+        write_chunk(&mut children, b"nTRN", &transform)?;
+        let mut shape = Vec::new();
+        shape.write_all(&i32::to_le_bytes(transform_id + 1))?;
+        write_dict(&mut shape, &name_pairs(model.shape_name))?;
+        shape.write_all(&i32::to_le_bytes(1))?;
+        shape.write_all(&i32::to_le_bytes(i as i32))?;
+        write_dict(&mut shape, &[])?;
+        write_chunk(&mut children, b"nSHP", &shape)?;
+    }
+    for (layer_id, name) in layers.iter().enumerate() {
+        let mut layer = Vec::new();
+        layer.write_all(&i32::to_le_bytes(layer_id as i32))?;
+        write_dict(&mut layer, &name_pairs(Some(*name).filter(|name| !name.is_empty())))?;
+        layer.write_all(&i32::to_le_bytes(-1))?;
+        write_chunk(&mut children, b"LAYR", &layer)?;
+    }
+
+    write_chunk(&mut children, b"RGBA", &palette.colors.concat())?;
+
+    let mut bytes = Vec::new();
+    bytes.write_all(b"VOX ")?;
+    bytes.write_all(&u32::to_le_bytes(200))?;
+    bytes.write_all(b"MAIN")?;
+    bytes.write_all(&[0; 4])?; // MAIN has no content
+    bytes.write_all(&size_field(children.len())?)?;
+    bytes.write_all(&children)?;
+    Ok(bytes)
+}
+
+fn name_pairs(name: Option<&str>) -> Vec<(&str, &str)> {
+    name.map(|name| ("_name", name)).into_iter().collect()
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) -> std::io::Result<()> {
+    bytes.write_all(id)?;
+    bytes.write_all(&size_field(content.len())?)?;
+    bytes.write_all(&[0; 4])?; // Only MAIN has children
+    bytes.write_all(content)
+}
+
+fn write_dict(bytes: &mut Vec<u8>, pairs: &[(&str, &str)]) -> std::io::Result<()> {
+    bytes.write_all(&size_field(pairs.len())?)?;
+    for (key, value) in pairs {
+        for s in [key, value] {
+            bytes.write_all(&size_field(s.len())?)?;
+            bytes.write_all(s.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum VoxError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    MissingMain,
+    Truncated { offset: usize, needed: usize, available: usize },
+    SizeMismatch { id: String, declared: usize, actual: usize },
+    MissingChunk(&'static str),
+    VoxelOutOfBounds([u8; 3]),
+    PaletteFull,
+    SizeOverflow,
+    SceneCycle(i32),
+    TranslationOverflow,
+    ModelTooLarge([u32; 3]),
+    SceneTooLarge([i64; 3]),
+    ChunkTooLarge { id: &'static str, declared: usize, limit: usize },
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoxError::BadMagic => write!(f, "VOX magic missing"),
+            VoxError::UnsupportedVersion(version) => {
+                write!(f, "VOX version {} unsupported", version)
+            },
+            VoxError::MissingMain => write!(f, "VOX MAIN chunk missing"),
+            VoxError::Truncated { offset, needed, available } => write!(
+                f,
+                "VOX truncated at byte {}: needed {} bytes, {} available",
+                offset, needed, available
+            ),
+            VoxError::SizeMismatch { id, declared, actual } => write!(
+                f,
+                "VOX {} chunk declares {} bytes of children but they take {}",
+                id, declared, actual
+            ),
+            VoxError::MissingChunk(id) => write!(f, "VOX {} chunk missing", id),
+            VoxError::VoxelOutOfBounds(xyz) => {
+                write!(f, "VOX voxel {:?} outside the model SIZE", xyz)
+            },
+            VoxError::PaletteFull => write!(f, "VOX palette holds at most 255 colors"),
+            VoxError::SizeOverflow => write!(f, "VOX chunk size exceeds u32"),
+            VoxError::SceneCycle(id) => write!(f, "VOX scene node {} reached more than once", id),
+            VoxError::TranslationOverflow => write!(f, "VOX scene translation overflows i32"),
+            VoxError::ModelTooLarge(size) => {
+                write!(f, "VOX model SIZE {:?} exceeds {} per axis", size, MAX_MODEL_SIZE)
+            },
+            VoxError::SceneTooLarge(extent) => {
+                write!(f, "VOX scene spans {:?}, more than {} cells", extent, MAX_SCENE_CELLS)
+            },
+            VoxError::ChunkTooLarge { id, declared, limit } => write!(
+                f,
+                "VOX {} chunk declares {} bytes of content, more than the {} allowed",
+                id, declared, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+impl From<VoxError> for std::io::Error {
+    fn from(error: VoxError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+// Checks the header and that every declared chunk size is consistent with
+// the bytes present, without decoding any voxels
+pub fn validate(bytes: &[u8]) -> Result<(), VoxError> {
+    if bytes.len() < 8 || &bytes[0..4] != b"VOX " {
+        return Err(VoxError::BadMagic);
+    }
+    let version = read_u32(bytes, 4)?;
+    if version != 150 && version != 200 {
+        return Err(VoxError::UnsupportedVersion(version));
+    }
+    if bytes.len() < 12 || &bytes[8..12] != b"MAIN" {
+        return Err(VoxError::MissingMain);
+    }
+    let end = validate_chunk(bytes, 8)?;
+    if end != bytes.len() {
+        return Err(VoxError::SizeMismatch {
+            id: "file".to_string(),
+            declared: end,
+            actual: bytes.len(),
+        });
+    }
+    Ok(())
+}
+
+// Validates the chunk at offset and its children, returning the chunk's end.
+// Nesting comes from the file, so the walk keeps its own stack of open chunks
+// rather than recursing.
+fn validate_chunk(bytes: &[u8], offset: usize) -> Result<usize, VoxError> {
+    // (offset, children start, end) of each chunk whose children are unchecked
+    let mut open: Vec<(usize, usize, usize)> = Vec::new();
+    let mut child = offset;
+    loop {
+        // Children must fit within their parent
+        let parent_end = open.last().map_or(bytes.len(), |(_, _, end)| *end);
+        let (children_start, end) = chunk_bounds(&bytes[..parent_end], child)?;
+        open.push((child, children_start, end));
+        child = children_start;
+        while let Some(&(chunk, children_start, end)) = open.last() {
+            if child < end {
+                break;
+            }
+            if child != end {
+                return Err(VoxError::SizeMismatch {
+                    id: String::from_utf8_lossy(&bytes[chunk..chunk + 4]).into_owned(),
+                    declared: end - children_start,
+                    actual: child - children_start,
+                });
+            }
+            open.pop();
+            if open.is_empty() {
+                return Ok(end);
+            }
+        }
+    }
+}
+
+// The children start and end of the chunk at offset
+fn chunk_bounds(bytes: &[u8], offset: usize) -> Result<(usize, usize), VoxError> {
+    let content_size = read_u32(bytes, offset + 4)? as usize;
+    let children_size = read_u32(bytes, offset + 8)? as usize;
+    let children_start = offset + 12 + content_size;
+    let end = children_start + children_size;
+    if end > bytes.len() {
+        return Err(VoxError::Truncated {
+            offset,
+            needed: 12 + content_size + children_size,
+            available: bytes.len() - offset,
+        });
+    }
+    Ok((children_start, end))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, VoxError> {
+    match bytes.get(offset..offset + 4) {
+        None => Err(VoxError::Truncated {
+            offset,
+            needed: 4,
+            available: bytes.len().saturating_sub(offset),
+        }),
+        Some(slice) => Ok(u32::from_le_bytes(slice.try_into().unwrap())),
+    }
+}
+
+// Splits a grid into per-cell indices into a palette of its distinct colors,
+// numbered in storage order of first appearance
+pub fn to_index_grid(grid: &Grid<Voxel>) -> (Grid<u32>, Vec<[u8; 4]>) {
+    let mut indices = Grid::new(grid.width(), grid.depth(), grid.height());
+    let mut color_indices = HashMap::new();
+    let mut palette = Vec::new();
+    for ((_, _, _, v), (_, _, _, i)) in grid.enumerate_cells().zip(indices.enumerate_cells_mut()) {
+        let rgba: [u8; 4] = v.as_rgba().try_into().unwrap();
+        *i = *color_indices.entry(rgba).or_insert_with(|| {
+            palette.push(rgba);
+            palette.len() as u32 - 1
+        });
+    }
+    (indices, palette)
+}
+
+pub fn from_index_grid(indices: &Grid<u32>, palette: &[[u8; 4]]) -> Grid<Voxel> {
+    let mut grid = Grid::new(indices.width(), indices.depth(), indices.height());
+    for ((_, _, _, i), (_, _, _, v)) in indices.enumerate_cells().zip(grid.enumerate_cells_mut()) {
+        *v = Voxel::from_rgba(&palette[*i as usize]);
+    }
+    grid
+}
+
+// Decodes the first model, or the whole scene of a version 200 file, using
+// the RGBA chunk for its colors
+pub fn decode(bytes: &[u8]) -> std::io::Result<Grid<Voxel>> {
+    decode_with_metadata(bytes).map(|(grid, _)| grid)
+}
+
+pub fn decode_with_metadata(
+    bytes: &[u8],
+) -> std::io::Result<(Grid<Voxel>, HashMap<String, String>)> {
+    validate(bytes)?;
+    let scene = read_u32(bytes, 4)? >= 200 && main_children(bytes).iter().any(|(id, _)| *id == b"nTRN");
+    let mut size = None;
+    let mut xyzi = None;
+    let mut palette = None;
+    let mut metadata = HashMap::new();
+    for (id, content) in main_children(bytes) {
+        match id {
+            b"SIZE" if size.is_none() => size = Some(content),
+            b"XYZI" if xyzi.is_none() => xyzi = Some(content),
+            b"RGBA" => palette = Some(content),
+            b"rOBJ" => {
+                let dict = read_dict(content, &mut 0)?;
+                if dict.iter().any(|(k, v)| k == "_type" && v == METADATA_TYPE) {
+                    metadata.extend(dict.into_iter().filter(|(k, _)| k != "_type"));
+                }
+            },
+            _ => {},
+        }
+    }
+    // Version 200 files place their models through the scene graph, so
+    // decode its whole arrangement, otherwise read the first model as laid out
+    if scene {
+        return Ok((stack_scene(&main_children(bytes))?, metadata));
+    }
+    let size = size.ok_or(VoxError::MissingChunk("SIZE"))?;
+    let xyzi = xyzi.ok_or(VoxError::MissingChunk("XYZI"))?;
+    let palette = palette.ok_or(VoxError::MissingChunk("RGBA"))?;
+    let grid = read_model(size, xyzi, palette)?;
+    Ok((grid, metadata))
+}
+
+// Decodes the first model, or the whole scene of a version 200 file like
+// decode, as its XYZI color indices, 0 for empty cells, and the palette those
+// index, so entry 0 is transparent and entry i is RGBA entry i - 1
+pub fn decode_indexed(bytes: &[u8]) -> std::io::Result<(Grid<u8>, Vec<[u8; 4]>)> {
+    validate(bytes)?;
+    let chunks = main_children(bytes);
+    let rgba = chunks
+        .iter()
+        .find(|(id, _)| *id == b"RGBA")
+        .map(|(_, content)| *content)
+        .ok_or(VoxError::MissingChunk("RGBA"))?;
+    if rgba.len() < 1024 {
+        let available = rgba.len();
+        return Err(VoxError::Truncated { offset: 0, needed: 1024, available }.into());
+    }
+    let mut palette = vec![[0; 4]];
+    palette.extend(rgba[..1020].chunks_exact(4).map(|c| <[u8; 4]>::try_from(c).unwrap()));
+    let scene = read_u32(bytes, 4)? >= 200 && chunks.iter().any(|(id, _)| *id == b"nTRN");
+    let sizes = chunks.iter().filter(|(id, _)| *id == b"SIZE");
+    let xyzis = chunks.iter().filter(|(id, _)| *id == b"XYZI");
+    let mut grids = Vec::new();
+    for ((_, size), (_, xyzi)) in sizes.zip(xyzis) {
+        grids.push(read_indices(size, xyzi)?);
+        // Only a scene needs more than the first model
+        if !scene {
+            break;
+        }
+    }
+    if scene {
+        let sizes: Vec<_> = grids.iter().map(|g| [g.width(), g.depth(), g.height()]).collect();
+        let models: Vec<_> = placements(&chunks, &sizes)?
+            .into_iter()
+            .map(|p| (grids[p.model].clone(), p.offset))
+            .collect();
+        return Ok((stack(&models, |i| *i != 0)?, palette));
+    }
+    let missing = if chunks.iter().any(|(id, _)| *id == b"SIZE") { "XYZI" } else { "SIZE" };
+    let grid = grids.into_iter().next().ok_or(VoxError::MissingChunk(missing))?;
+    Ok((grid, palette))
+}
+
+// Decodes chunk by chunk like decode, buffering only the first SIZE and
+// XYZI and the RGBA chunk while skipping everything else. Version 200 files
+// also buffer every model and scene graph node, to stack their scene.
+pub fn decode_reader(r: &mut impl Read) -> std::io::Result<Grid<Voxel>> {
+    let mut header = [0; 20];
+    r.read_exact(&mut header)?;
+    if &header[0..4] != b"VOX " {
+        return Err(VoxError::BadMagic.into());
+    }
+    let version = read_u32(&header, 4)?;
+    if version != 150 && version != 200 {
+        return Err(VoxError::UnsupportedVersion(version).into());
+    }
+    if &header[8..12] != b"MAIN" {
+        return Err(VoxError::MissingMain.into());
+    }
+    let mut remaining = read_u32(&header, 16)? as u64;
+    skip(r, read_u32(&header, 12)? as u64)?;
+    let scene_version = version >= 200;
+    // (id, content) of each buffered chunk in file order
+    let mut chunks: Vec<([u8; 4], Vec<u8>)> = Vec::new();
+    let seen = |chunks: &[([u8; 4], Vec<u8>)], id: &[u8; 4]| chunks.iter().any(|(c, _)| c == id);
+    while remaining > 0 {
+        let mut chunk = [0; 12];
+        r.read_exact(&mut chunk)?;
+        let content_size = read_u32(&chunk, 4)? as u64;
+        let children_size = read_u32(&chunk, 8)? as u64;
+        let id: [u8; 4] = chunk[0..4].try_into().unwrap();
+        // The sizes come from the stream, so cap each buffered chunk at the
+        // largest content its id can have before allocating it
+        let limit = match &id {
+            b"SIZE" if scene_version || !seen(&chunks, b"SIZE") => Some(("SIZE", 12)),
+            b"XYZI" if scene_version || !seen(&chunks, b"XYZI") => {
+                Some(("XYZI", 4 + 4 * 256 * 256 * 256))
+            },
+            b"RGBA" => Some(("RGBA", 1024)),
+            b"nTRN" if scene_version => Some(("nTRN", MAX_NODE_SIZE)),
+            b"nGRP" if scene_version => Some(("nGRP", MAX_NODE_SIZE)),
+            b"nSHP" if scene_version => Some(("nSHP", MAX_NODE_SIZE)),
+            _ => None,
+        };
+        match limit {
+            None => skip(r, content_size)?,
+            Some((name, limit)) if content_size > limit as u64 => {
+                let declared = content_size as usize;
+                return Err(VoxError::ChunkTooLarge { id: name, declared, limit }.into());
+            },
+            Some(_) => {
+                let mut content = vec![0; content_size as usize];
+                r.read_exact(&mut content)?;
+                chunks.push((id, content));
+            },
+        }
+        skip(r, children_size)?;
+        remaining = remaining.saturating_sub(12 + content_size + children_size);
+    }
+    let chunks: Vec<(&[u8], &[u8])> =
+        chunks.iter().map(|(id, content)| (&id[..], &content[..])).collect();
+    if scene_version && chunks.iter().any(|(id, _)| *id == b"nTRN") {
+        return Ok(stack_scene(&chunks)?);
+    }
+    let find = |chunk_id: &[u8]| chunks.iter().find(|(id, _)| *id == chunk_id).map(|(_, c)| *c);
+    let size = find(b"SIZE").ok_or(VoxError::MissingChunk("SIZE"))?;
+    let xyzi = find(b"XYZI").ok_or(VoxError::MissingChunk("XYZI"))?;
+    // Like decode, the last RGBA chunk wins
+    let palette = chunks
+        .iter()
+        .rev()
+        .find(|(id, _)| *id == b"RGBA")
+        .map(|(_, content)| *content)
+        .ok_or(VoxError::MissingChunk("RGBA"))?;
+    Ok(read_model(size, xyzi, palette)?)
+}
+
+// Scene graph nodes hold a few dicts or child lists, far below this
+const MAX_NODE_SIZE: usize = 1 << 20;
+
+fn skip(r: &mut impl Read, count: u64) -> std::io::Result<()> {
+    if std::io::copy(&mut r.take(count), &mut std::io::sink())? < count {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+// Builds a model from its SIZE and XYZI chunks and the RGBA palette
+fn read_model(size: &[u8], xyzi: &[u8], palette: &[u8]) -> Result<Grid<Voxel>, VoxError> {
+    let [width, depth, height] = read_size(size)?;
+    let mut grid = Grid::new(width, depth, height);
+    let count = read_u32(xyzi, 0)? as usize;
+    for (content, needed) in [(xyzi, 4 + count * 4), (palette, 1024)] {
+        if content.len() < needed {
+            let available = content.len();
+            return Err(VoxError::Truncated { offset: 0, needed, available });
+        }
+    }
+    for entry in xyzi[4..4 + count * 4].chunks_exact(4) {
+        let (x, y, z, i) = (entry[0] as u32, entry[1] as u32, entry[2] as u32, entry[3] as usize);
+        if grid.id(x, y, z).is_none() {
+            return Err(VoxError::VoxelOutOfBounds([entry[0], entry[1], entry[2]]));
+        }
+        // Palette index i is stored at RGBA entry i - 1
+        let rgba = &palette[(i.max(1) - 1) * 4..i.max(1) * 4];
+        *grid.get_mut(x, y, z) = Voxel::from_rgba(rgba);
+    }
+    Ok(grid)
+}
+
+// Builds a model from its SIZE and XYZI chunks as palette indices
+fn read_indices(size: &[u8], xyzi: &[u8]) -> Result<Grid<u8>, VoxError> {
+    let [width, depth, height] = read_size(size)?;
+    let mut grid = Grid::new(width, depth, height);
+    let count = read_u32(xyzi, 0)? as usize;
+    if xyzi.len() < 4 + count * 4 {
+        let (needed, available) = (4 + count * 4, xyzi.len());
+        return Err(VoxError::Truncated { offset: 0, needed, available });
+    }
+    for entry in xyzi[4..4 + count * 4].chunks_exact(4) {
+        let (x, y, z) = (entry[0] as u32, entry[1] as u32, entry[2] as u32);
+        if grid.id(x, y, z).is_none() {
+            return Err(VoxError::VoxelOutOfBounds([entry[0], entry[1], entry[2]]));
+        }
+        *grid.get_mut(x, y, z) = entry[3];
+    }
+    Ok(grid)
+}
+
+// XYZI coordinates are single bytes, so no model can be larger than this
+const MAX_MODEL_SIZE: u32 = 256;
+
+fn read_size(size: &[u8]) -> Result<[u32; 3], VoxError> {
+    let dims = [read_u32(size, 0)?, read_u32(size, 4)?, read_u32(size, 8)?];
+    if dims.iter().any(|d| *d > MAX_MODEL_SIZE) {
+        return Err(VoxError::ModelTooLarge(dims));
+    }
+    Ok(dims)
+}
+
+// The (id, content) of each child of MAIN in a validated file
+fn main_children(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut offset = 20 + u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+    while offset < bytes.len() {
+        let content_size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+        let children_size = u32::from_le_bytes(bytes[offset + 8..offset + 12].try_into().unwrap());
+        let content = offset + 12..offset + 12 + content_size as usize;
+        chunks.push((&bytes[offset..offset + 4], &bytes[content.clone()]));
+        offset = content.end + children_size as usize;
+    }
+    chunks
+}
+
+fn read_string(bytes: &[u8], offset: &mut usize) -> Result<String, VoxError> {
+    let len = read_u32(bytes, *offset)? as usize;
+    let start = *offset + 4;
+    match bytes.get(start..start + len) {
+        None => Err(VoxError::Truncated {
+            offset: start,
+            needed: len,
+            available: bytes.len().saturating_sub(start),
+        }),
+        Some(slice) => {
+            *offset = start + len;
+            Ok(String::from_utf8_lossy(slice).into_owned())
+        },
+    }
+}
+
+fn read_dict(bytes: &[u8], offset: &mut usize) -> Result<Vec<(String, String)>, VoxError> {
+    let count = read_u32(bytes, *offset)?;
+    *offset += 4;
+    let mut pairs = Vec::new();
+    for _ in 0..count {
+        let key = read_string(bytes, offset)?;
+        let value = read_string(bytes, offset)?;
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+// Decodes each file and stacks it with its min corner at the offset
+pub fn load_and_stack(paths: &[(&Path, [i32; 3])]) -> std::io::Result<Grid<Voxel>> {
+    let mut models = Vec::with_capacity(paths.len());
+    for (path, offset) in paths {
+        models.push((decode(&std::fs::read(path)?)?, *offset));
+    }
+    Ok(stack(&models, |v| v.as_rgba()[3] > 0)?)
+}
+
+// Stacks every placed model of the (id, content) children of MAIN
+fn stack_scene(chunks: &[(&[u8], &[u8])]) -> Result<Grid<Voxel>, VoxError> {
+    let models: Vec<_> = read_scene(chunks)?.into_iter().map(|m| (m.grid, m.offset)).collect();
+    stack(&models, |v| v.as_rgba()[3] > 0)
+}
+
+// Offsets come from the file, so the stacked grid's cell count is bounded
+// before it is allocated. This allows four full 256^3 models.
+const MAX_SCENE_CELLS: i64 = 1 << 26;
+
+// Places each grid with its min corner at the offset in a grid sized to fit
+// them all, drawing later grids over earlier ones where opaque
+fn stack<T>(models: &[(Grid<T>, [i32; 3])], opaque: fn(&T) -> bool) -> Result<Grid<T>, VoxError>
+where
+    T: Codec + Copy,
+{
+    let mut min = [i64::MAX; 3];
+    let mut max = [i64::MIN; 3];
+    for (grid, offset) in models {
+        let dims = [grid.width(), grid.depth(), grid.height()];
+        for i in 0..3 {
+            min[i] = min[i].min(offset[i] as i64);
+            max[i] = max[i].max(offset[i] as i64 + dims[i] as i64);
+        }
+    }
+    if models.is_empty() {
+        return Ok(Grid::new(0, 0, 0));
+    }
+    let extent = [0, 1, 2].map(|i| max[i] - min[i]);
+    let cells = extent.iter().try_fold(1i64, |cells, e| cells.checked_mul(*e));
+    if cells.is_none_or(|cells| cells > MAX_SCENE_CELLS) {
+        return Err(VoxError::SceneTooLarge(extent));
+    }
+    let dims = extent.map(|e| e as u32);
+    let mut stacked = Grid::new(dims[0], dims[1], dims[2]);
+    for (grid, offset) in models {
+        let at = [0, 1, 2].map(|i| (offset[i] as i64 - min[i]) as u32);
+        for (x, y, z, v) in grid.enumerate_cells() {
+            if opaque(v) {
+                *stacked.get_mut(x + at[0], y + at[1], z + at[2]) = *v;
+            }
+        }
+    }
+    Ok(stacked)
+}
+
+enum Node {
+    Transform { name: Option<String>, child: i32, translation: [i32; 3] },
+    Group { children: Vec<i32> },
+    Shape { name: Option<String>, model: i32 },
+}
+
+// Decodes every model with its placement, walking the scene graph when there
+// is one. Offsets are the world position of each model's min corner.
+pub fn decode_models(bytes: &[u8]) -> std::io::Result<Vec<Model>> {
+    validate(bytes)?;
+    Ok(read_scene(&main_children(bytes))?)
+}
+
+// The placed models of the (id, content) children of MAIN
+fn read_scene(chunks: &[(&[u8], &[u8])]) -> Result<Vec<Model>, VoxError> {
+    let palette = chunks
+        .iter()
+        .find(|(id, _)| *id == b"RGBA")
+        .map(|(_, content)| *content)
+        .ok_or(VoxError::MissingChunk("RGBA"))?;
+    let sizes = chunks.iter().filter(|(id, _)| *id == b"SIZE");
+    let xyzis = chunks.iter().filter(|(id, _)| *id == b"XYZI");
+    let grids = sizes
+        .zip(xyzis)
+        .map(|((_, size), (_, xyzi))| read_model(size, xyzi, palette))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sizes: Vec<_> = grids.iter().map(|g| [g.width(), g.depth(), g.height()]).collect();
+    Ok(placements(chunks, &sizes)?
+        .into_iter()
+        .map(|p| Model {
+            grid: grids[p.model].clone(),
+            offset: p.offset,
+            name: p.name,
+            shape_name: p.shape_name,
+        })
+        .collect())
+}
+
+struct Placement {
+    model: usize,
+    offset: [i32; 3],
+    name: Option<String>,
+    shape_name: Option<String>,
+}
+
+// Places each model of the given sizes by walking the scene graph, or at the
+// origin in file order when there is none
+fn placements(chunks: &[(&[u8], &[u8])], sizes: &[[u32; 3]]) -> Result<Vec<Placement>, VoxError> {
+    let mut nodes = HashMap::new();
+    for (id, content) in chunks {
+        let offset = &mut 4;
+        let node = match *id {
+            b"nTRN" => {
+                let name = dict_name(read_dict(content, offset)?);
+                let child = read_u32(content, *offset)? as i32;
+                // Skip the reserved id, layer id and frame count
+                *offset += 16;
+                let frame = read_dict(content, offset)?;
+                let mut translation = [0; 3];
+                if let Some((_, t)) = frame.iter().find(|(k, _)| k == "_t") {
+                    for (c, value) in translation.iter_mut().zip(t.split_whitespace()) {
+                        *c = value.parse().unwrap_or(0);
+                    }
+                }
+                Node::Transform { name, child, translation }
+            },
+            b"nGRP" => {
+                read_dict(content, offset)?;
+                let count = read_u32(content, *offset)?;
+                let children = (0..count as usize)
+                    .map(|i| read_u32(content, *offset + 4 + i * 4).map(|c| c as i32))
+                    .collect::<Result<_, _>>()?;
+                Node::Group { children }
+            },
+            b"nSHP" => {
+                let name = dict_name(read_dict(content, offset)?);
+                let model = read_u32(content, *offset + 4)? as i32;
+                Node::Shape { name, model }
+            },
+            _ => continue,
+        };
+        nodes.insert(read_u32(content, 0)? as i32, node);
+    }
+    if nodes.is_empty() {
+        return Ok((0..sizes.len())
+            .map(|model| Placement { model, offset: [0; 3], name: None, shape_name: None })
+            .collect());
+    }
+    let mut placed = Vec::new();
+    // (node, accumulated translation, nearest transform name)
+    let mut stack = vec![(0, [0i32; 3], None)];
+    // The scene graph is a tree, so a node reached twice means a cycle
+    let mut visited = HashSet::new();
+    while let Some((id, translation, name)) = stack.pop() {
+        if !visited.insert(id) {
+            return Err(VoxError::SceneCycle(id));
+        }
+        match nodes.get(&id) {
+            None => {},
+            Some(Node::Transform { name: own, child, translation: t }) => {
+                let mut sum = [0; 3];
+                for i in 0..3 {
+                    sum[i] = translation[i]
+                        .checked_add(t[i])
+                        .ok_or(VoxError::TranslationOverflow)?;
+                }
+                stack.push((*child, sum, own.clone().or(name)));
+            },
+            Some(Node::Group { children }) => {
+                for child in children.iter().rev() {
+                    stack.push((*child, translation, None));
+                }
+            },
+            Some(Node::Shape { name: shape_name, model }) => {
+                let model = *model as usize;
+                let size = sizes.get(model).ok_or(VoxError::MissingChunk("XYZI"))?;
+                let mut offset = [0; 3];
+                for i in 0..3 {
+                    offset[i] = translation[i]
+                        .checked_sub((size[i] / 2) as i32)
+                        .ok_or(VoxError::TranslationOverflow)?;
+                }
+                placed.push(Placement { model, offset, name, shape_name: shape_name.clone() });
+            },
+        }
+    }
+    Ok(placed)
+}
+
+fn dict_name(dict: Vec<(String, String)>) -> Option<String> {
+    dict.into_iter().find(|(k, _)| k == "_name").map(|(_, v)| v)
+}